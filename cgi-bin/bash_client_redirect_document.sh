@@ -0,0 +1,7 @@
+#!/bin/bash
+
+read INDATA
+printf "Location: https://www.example.com\n"
+printf "Status: 301 Moved Permanently\n"
+printf "Content-Type: text/html\n\n"
+printf "This page has moved to <a href=\"https://www.example.com\">https://www.example.com</a>\n"
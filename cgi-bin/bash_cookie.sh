@@ -0,0 +1,9 @@
+#!/bin/bash
+
+VISITS=$(printf "${HTTP_COOKIE}" | tr ';' '\n' | grep -o "visits=[0-9]*" | cut -d= -f2)
+VISITS=$(( ${VISITS:-0} + 1 ))
+
+printf "Content-Type: text/plain\n"
+printf "Set-Cookie: visits=${VISITS}; Path=/\n"
+printf "Cache-Control: no-store\n\n"
+printf "You have visited this page ${VISITS} time(s)\n"
@@ -0,0 +1,4 @@
+#!/bin/bash
+
+read INDATA
+printf "Location: /cgi-bin/bash_path_info.sh/redirected?from=bash_local_redirect_script.sh\n\n"
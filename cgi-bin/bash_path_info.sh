@@ -0,0 +1,6 @@
+#!/bin/bash
+
+printf "Content-Type: text/plain\n\n"
+printf "SCRIPT_NAME: ${SCRIPT_NAME}\n"
+printf "PATH_INFO: ${PATH_INFO}\n"
+printf "PATH_TRANSLATED: ${PATH_TRANSLATED}\n"
@@ -0,0 +1,7 @@
+#!/bin/bash
+
+printf "Content-Type: text/plain\n\n"
+for STEP in 1 2 3 4 5; do
+    printf "Step ${STEP} of 5 done\n"
+    sleep 1
+done
@@ -0,0 +1,11 @@
+#!/bin/bash
+
+printf "HTTP/1.1 200 OK\r\n"
+printf "Content-Type: text/plain\r\n"
+printf "Cache-Control: no-store\r\n"
+printf "Connection: close\r\n\r\n"
+for COUNT in 3 2 1; do
+    printf "${COUNT}...\n"
+    sleep 1
+done
+printf "Done!\n"
@@ -0,0 +1,108 @@
+#!/usr/bin/env python3
+# Minimal FastCGI responder, using only the standard library. It accepts
+# connections on the socket it gets as its standard input, and counts the
+# requests handled by the process to show that it keeps running between them.
+
+import os
+import socket
+import struct
+
+FCGI_BEGIN_REQUEST = 1
+FCGI_END_REQUEST = 3
+FCGI_PARAMS = 4
+FCGI_STDIN = 5
+FCGI_STDOUT = 6
+FCGI_KEEP_CONN = 1
+
+
+def read_exactly(connection, length):
+    data = b""
+    while len(data) < length:
+        chunk = connection.recv(length - len(data))
+        if not chunk:
+            raise EOFError
+        data += chunk
+    return data
+
+
+def read_record(connection):
+    _, record_type, request_id, content_length, padding_length, _ = struct.unpack(
+        ">BBHHBB", read_exactly(connection, 8)
+    )
+    content = read_exactly(connection, content_length + padding_length)
+    return record_type, request_id, content[:content_length]
+
+
+def write_record(connection, record_type, request_id, content):
+    padding_length = -len(content) % 8
+    header = struct.pack(
+        ">BBHHBB", 1, record_type, request_id, len(content), padding_length, 0
+    )
+    connection.sendall(header + content + b"\0" * padding_length)
+
+
+def decode_params(data):
+    params = {}
+    position = 0
+    while position < len(data):
+        lengths = []
+        for _ in range(2):
+            if data[position] < 128:
+                lengths.append(data[position])
+                position += 1
+            else:
+                lengths.append(struct.unpack(">I", data[position : position + 4])[0] & 0x7FFFFFFF)
+                position += 4
+        name = data[position : position + lengths[0]].decode()
+        value = data[position + lengths[0] : position + lengths[0] + lengths[1]].decode()
+        params[name] = value
+        position += lengths[0] + lengths[1]
+    return params
+
+
+handled_requests = 0
+
+
+def handle_request(connection):
+    global handled_requests
+
+    keep_connection = False
+    params = b""
+    body = b""
+    while True:
+        record_type, request_id, content = read_record(connection)
+        if record_type == FCGI_BEGIN_REQUEST:
+            keep_connection = content[2] & FCGI_KEEP_CONN
+        elif record_type == FCGI_PARAMS:
+            params += content
+        elif record_type == FCGI_STDIN and content:
+            body += content
+        elif record_type == FCGI_STDIN:
+            break
+
+    params = decode_params(params)
+    handled_requests += 1
+    output = (
+        "Content-Type: text/plain\r\n\r\n"
+        f"Process {os.getpid()} handled {handled_requests} request(s).\n"
+        f"Path info: {params.get('PATH_INFO', '')}\n"
+        f"Query string: {params.get('QUERY_STRING', '')}\n"
+        f"Body: {body.decode(errors='replace')}\n"
+    ).encode()
+
+    write_record(connection, FCGI_STDOUT, request_id, output)
+    write_record(connection, FCGI_STDOUT, request_id, b"")
+    write_record(connection, FCGI_END_REQUEST, request_id, b"\0" * 8)
+    return keep_connection
+
+
+listener = socket.socket(fileno=0)
+while True:
+    connection, _ = listener.accept()
+    try:
+        while handle_request(connection):
+            pass
+    except EOFError:
+        pass
+    finally:
+        connection.close()
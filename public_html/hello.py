@@ -0,0 +1,12 @@
+import os
+
+print("Content-Type: text/html")
+print()
+print("<html>")
+print("<head><title>Hello from public_html</title></head>")
+print("<body>")
+print("<h2>Hello from a Python script living among the static files!</h2>")
+print("<p>Script name: %s</p>" % os.environ.get("SCRIPT_NAME", ""))
+print("<p>Extra path: %s</p>" % os.environ.get("PATH_INFO", ""))
+print("</body>")
+print("</html>")
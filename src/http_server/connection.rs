@@ -1,24 +1,162 @@
-use std::{io::prelude::*, net::TcpStream};
+use std::{
+    net::TcpStream,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
 
-use http::{Request, Response, StatusCode};
+use http::{header, HeaderValue, Method, Request, Response, StatusCode, Version};
 
-use log::{debug, info};
+use log::{debug, info, warn};
 
 use crate::http_server::{
-    request::request::{load_request, RequestHandler},
+    request::request::{RequestHandler, RequestId, RequestReader},
     response::generate_error_response,
-    response::response_to_string,
+    response::{set_body_framing, write_response, LocalRedirect, ResponseBody},
 };
 
-type RequestHandlerList = Vec<Box<dyn RequestHandler<String> + Sync + Send>>;
+pub type RequestHandlerList = Vec<Box<dyn RequestHandler<Vec<u8>> + Sync + Send>>;
+
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024; // 1MB
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+const DEFAULT_MAX_PERSISTENT_CONNECTIONS: usize = 3;
+const DEFAULT_MAX_LOCAL_REDIRECTS: usize = 10;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Tunable limits applied to every connection handled by a
+/// `ConnectionHandler`.
+pub struct ConnectionSettings {
+    /// Maximum accepted size for a request body, in bytes.
+    pub max_body_size: usize,
+    /// How long a persistent connection may stay idle waiting for the next
+    /// request before it is closed.
+    pub keep_alive_timeout: Duration,
+    /// Maximum number of requests served over a single connection.
+    pub max_requests_per_connection: usize,
+    /// Maximum number of connections kept open between requests at once.
+    /// Connections handled while this many are already open are closed after
+    /// each response, so that idle persistent connections can't hold every
+    /// worker thread (it should therefore be lower than the thread pool
+    /// size).
+    pub max_persistent_connections: usize,
+    /// Maximum number of local redirects followed while handling a single
+    /// request, to prevent infinite redirect chains.
+    pub max_local_redirects: usize,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> ConnectionSettings {
+        ConnectionSettings {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            max_requests_per_connection: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+            max_persistent_connections: DEFAULT_MAX_PERSISTENT_CONNECTIONS,
+            max_local_redirects: DEFAULT_MAX_LOCAL_REDIRECTS,
+        }
+    }
+}
 
 pub struct ConnectionHandler {
     request_handlers: RequestHandlerList,
+    settings: ConnectionSettings,
+    open_connections: AtomicUsize,
+}
+
+/// Keeps a connection counted among the open connections of a
+/// `ConnectionHandler` until it is dropped.
+struct OpenConnection<'a> {
+    open_connections: &'a AtomicUsize,
+}
+
+impl<'a> OpenConnection<'a> {
+    fn new(open_connections: &'a AtomicUsize) -> OpenConnection<'a> {
+        open_connections.fetch_add(1, Ordering::SeqCst);
+        OpenConnection { open_connections }
+    }
+
+    /// Returns the number of connections currently open, this one included.
+    ///
+    fn count(&self) -> usize {
+        self.open_connections.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Checks whether the client asked for the connection to be kept open after
+/// the given request. HTTP/1.1 connections are persistent unless the client
+/// sends `Connection: close`, while HTTP/1.0 ones must explicitly ask for
+/// `Connection: keep-alive`.
+///
+fn client_wants_keep_alive(request: &Request<Vec<u8>>) -> bool {
+    let has_connection_option = |option: &str| {
+        request
+            .headers()
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(option))
+    };
+
+    match request.version() {
+        Version::HTTP_11 => !has_connection_option("close"),
+        Version::HTTP_10 => has_connection_option("keep-alive"),
+        _ => false,
+    }
+}
+
+/// Builds the request to be processed after a local redirect to `location`.
+/// As for CGI local redirects in Apache's mod_cgi, the new request is a GET
+/// request without a body (HEAD requests stay HEAD requests so that no body
+/// is sent back), since the target may well be a static resource. It keeps
+/// the other headers and the id of the original request.
+///
+fn redirected_request(
+    request: &Request<Vec<u8>>,
+    location: &str,
+) -> Result<Request<Vec<u8>>, http::Error> {
+    let method = if request.method() == Method::HEAD {
+        Method::HEAD
+    } else {
+        Method::GET
+    };
+
+    let mut redirected_request = Request::builder()
+        .method(method)
+        .uri(location)
+        .version(request.version())
+        .body(Vec::new())?;
+
+    if let Some(request_id) = request.extensions().get::<RequestId>() {
+        redirected_request.extensions_mut().insert(*request_id);
+    }
+
+    let headers = redirected_request.headers_mut();
+    for (header_name, header_value) in request.headers() {
+        if header_name != header::CONTENT_LENGTH && header_name != header::CONTENT_TYPE {
+            headers.append(header_name, header_value.clone());
+        }
+    }
+
+    Ok(redirected_request)
 }
 
 impl ConnectionHandler {
-    pub fn new(request_handlers: RequestHandlerList) -> ConnectionHandler {
-        ConnectionHandler { request_handlers }
+    pub fn new(
+        request_handlers: RequestHandlerList,
+        settings: ConnectionSettings,
+    ) -> ConnectionHandler {
+        ConnectionHandler {
+            request_handlers,
+            settings,
+            open_connections: AtomicUsize::new(0),
+        }
     }
 
     /// Handles a single incoming HTTP request using a suitable handler.
@@ -26,52 +164,303 @@ impl ConnectionHandler {
     /// Receives the request information as well as the TCP stream from which
     /// the request was read. Handlers supplied to the ConnectionHandler are
     /// tried in order, and the first `Some` response available is returned.
-    pub fn handle_request(&self, request: Request<String>, stream: &TcpStream) -> Response<String> {
+    ///
+    /// Responses asking for a local redirect go through the handlers again
+    /// with the redirected request, up to the configured maximum number of
+    /// redirects.
+    pub fn handle_request(
+        &self,
+        request: Request<Vec<u8>>,
+        stream: &TcpStream,
+    ) -> Response<ResponseBody> {
+        let mut request = request;
+        let mut followed_redirects = 0;
+
+        loop {
+            let response = self.dispatch_request(&request, stream);
+            let location = match response.extensions().get::<LocalRedirect>() {
+                None => return response,
+                Some(local_redirect) => local_redirect.location.clone(),
+            };
+
+            if followed_redirects >= self.settings.max_local_redirects {
+                warn!(
+                    "Too many local redirects, giving up at {} -> {}",
+                    request.uri(),
+                    location
+                );
+                return generate_error_response(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            followed_redirects += 1;
+
+            debug!("Local redirect from {} to {}", request.uri(), location);
+            request = match redirected_request(&request, &location) {
+                Err(_) => return generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
+                Ok(redirected_request) => redirected_request,
+            };
+        }
+    }
+
+    /// Passes a request to the first handler able to process it.
+    ///
+    fn dispatch_request(
+        &self,
+        request: &Request<Vec<u8>>,
+        stream: &TcpStream,
+    ) -> Response<ResponseBody> {
         let mut response = None;
         for handler in &self.request_handlers {
-            response = response.or(handler.handle_request(stream, &request));
-            if let Some(_) = response {
+            response = response.or(handler.handle_request(stream, request));
+            if response.is_some() {
                 break;
             }
         }
 
-        let mut response =
-            response.unwrap_or(generate_error_response(StatusCode::INTERNAL_SERVER_ERROR));
+        response.unwrap_or(generate_error_response(StatusCode::INTERNAL_SERVER_ERROR))
+    }
 
-        if request.method() == "HEAD" {
-            *response.body_mut() = String::from("");
+    /// Sets the headers telling the client whether the connection will be
+    /// kept open after the current response, and if so for how many more
+    /// requests (`remaining_requests`).
+    ///
+    fn set_connection_headers(
+        &self,
+        response: &mut Response<ResponseBody>,
+        keep_alive: bool,
+        remaining_requests: usize,
+    ) {
+        let headers = response.headers_mut();
+        if keep_alive {
+            let keep_alive_value = format!(
+                "timeout={}, max={}",
+                self.settings.keep_alive_timeout.as_secs(),
+                remaining_requests
+            );
+            headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive"));
+            if let Ok(keep_alive_value) = HeaderValue::from_str(&keep_alive_value) {
+                headers.insert("keep-alive", keep_alive_value);
+            }
+        } else {
+            headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
         }
-
-        response
     }
 
-    /// Reads an HTTP request from a TCP stream and writes the corresponding
-    /// response back to it.
+    /// Reads HTTP requests from a TCP stream and writes the corresponding
+    /// responses back to it.
+    ///
+    /// Requests are read and answered one at a time, so responses to
+    /// requests pipelined by the client are written back in the same order
+    /// the requests were sent. Data received past the end of a request is
+    /// kept by the `RequestReader` for the next one.
     ///
-    /// # Panics
+    /// Connections are persistent whenever the client allows it: requests
+    /// keep being served over the same stream until the client asks for it
+    /// to be closed, the connection stays idle for longer than the
+    /// configured keep-alive timeout, the maximum number of requests per
+    /// connection is reached, too many persistent connections are already
+    /// open (see `ConnectionSettings::max_persistent_connections`), a
+    /// response body of unknown length is sent to
+    /// an HTTP/1.0 client or an invalid request is received. The
+    /// connection is also dropped if the stream can't be written to or
+    /// flushed (for instance if it was closed by the client).
     ///
-    /// The `handle_connection` method panics if the stream can't be written to
-    /// or flushed (for instance if it was closed).
+    pub fn handle_connection(&self, stream: TcpStream) {
+        info!("New connection received");
+        let open_connection = OpenConnection::new(&self.open_connections);
+        if let Err(error) = stream.set_read_timeout(Some(self.settings.keep_alive_timeout)) {
+            debug!("Couldn't set the keep-alive timeout: {:?}", error);
+        }
+
+        let mut request_reader = RequestReader::new(&stream);
+        let mut handled_requests = 0;
+        loop {
+            let request = match request_reader.load_request(self.settings.max_body_size) {
+                Ok(None) => {
+                    info!("Connection closed or idle, stopping");
+                    break;
+                }
+                Ok(Some(mut request)) => {
+                    let request_id = RequestId(NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed));
+                    info!("New request received: {}", request_id);
+                    request.extensions_mut().insert(request_id);
+                    Ok(request)
+                }
+                Err(status) => {
+                    info!("Invalid request received");
+                    Err(status)
+                }
+            };
+            debug!("{:?}", request);
+            handled_requests += 1;
+
+            let (mut response, keep_alive) = match request {
+                Ok(request) => {
+                    let keep_alive = client_wants_keep_alive(&request)
+                        && handled_requests < self.settings.max_requests_per_connection
+                        && open_connection.count() <= self.settings.max_persistent_connections;
+                    let version = request.version();
+                    let is_head = request.method() == Method::HEAD;
+
+                    let mut response = self.handle_request(request, &stream);
+                    let keep_alive =
+                        set_body_framing(&mut response, version, is_head) && keep_alive;
+
+                    (response, keep_alive)
+                }
+                Err(status) => {
+                    let mut response = generate_error_response(status);
+                    set_body_framing(&mut response, Version::HTTP_11, false);
+                    (response, false)
+                }
+            };
+            self.set_connection_headers(
+                &mut response,
+                keep_alive,
+                self.settings
+                    .max_requests_per_connection
+                    .saturating_sub(handled_requests),
+            );
+
+            info!("Writing response");
+            debug!("Response: {:?}", response);
+
+            if let Err(error) = write_response(&stream, response) {
+                info!("Error writing response to the TCP stream: {:?}", error);
+                break;
+            }
+
+            info!("Finished writing response");
+
+            if !keep_alive {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{Shutdown, TcpListener},
+    };
+
+    use crate::http_server::request::{
+        cgi_request::cgi_handler::{CgiRequestHandler, CgiSettings},
+        static_request::static_handler::StaticRequestHandler,
+    };
+
+    #[test]
+    fn redirected_request_is_a_get_request() {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/cgi-bin/form.sh")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::CONTENT_LENGTH, "3")
+            .header(header::COOKIE, "a=1")
+            .body(b"a=1".to_vec())
+            .unwrap();
+
+        let redirected = redirected_request(&request, "/cgi-bin/done.sh?from=form").unwrap();
+
+        assert_eq!(redirected.method(), Method::GET);
+        assert_eq!(redirected.uri().path(), "/cgi-bin/done.sh");
+        assert_eq!(redirected.uri().query(), Some("from=form"));
+        assert_eq!(redirected.headers()[header::COOKIE], "a=1");
+        assert!(redirected.headers().get(header::CONTENT_TYPE).is_none());
+        assert!(redirected.headers().get(header::CONTENT_LENGTH).is_none());
+        assert!(redirected.body().is_empty());
+    }
+
+    /// Sends the given requests over a connection handled by a handler using
+    /// the given settings, and returns everything written back.
     ///
-    pub fn handle_connection(&self, mut stream: TcpStream) {
-        info!("New request received");
-        let request = load_request(&mut stream);
-        debug!("{:?}", request);
-
-        let response = match request {
-            Ok(request) => self.handle_request(request, &stream),
-            Err(status) => generate_error_response(status),
-        };
-
-        let response_text = response_to_string(response);
-        info!("Writing response");
-        debug!("Response: \n{response_text}\n");
-
-        stream
-            .write_all(response_text.as_bytes())
-            .expect("Error writing response to the TCP Stream");
-        stream.flush().expect("Error flushing TCP stream");
-
-        info!("Finished writing response");
+    fn exchange(settings: ConnectionSettings, requests: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let connection_handler = ConnectionHandler::new(
+            vec![Box::new(StaticRequestHandler::new(
+                String::from("public_html"),
+                Vec::new(),
+            ))],
+            settings,
+        );
+
+        client.write_all(requests.as_bytes()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        connection_handler.handle_connection(stream);
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn keep_alive_header_counts_remaining_requests() {
+        let output = exchange(
+            ConnectionSettings {
+                max_requests_per_connection: 3,
+                ..ConnectionSettings::default()
+            },
+            "HEAD / HTTP/1.1\r\n\r\nHEAD / HTTP/1.1\r\n\r\nHEAD / HTTP/1.1\r\n\r\n",
+        );
+
+        assert!(output.contains("keep-alive: timeout=5, max=2\r\n"));
+        assert!(output.contains("keep-alive: timeout=5, max=1\r\n"));
+        assert!(output.ends_with("connection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn connections_over_the_persistent_limit_are_closed() {
+        let output = exchange(
+            ConnectionSettings {
+                max_persistent_connections: 0,
+                ..ConnectionSettings::default()
+            },
+            "HEAD / HTTP/1.1\r\n\r\nHEAD / HTTP/1.1\r\n\r\n",
+        );
+
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(output.contains("connection: close\r\n"));
+    }
+
+    #[test]
+    fn posted_request_is_redirected_to_static_page() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let connection_handler = ConnectionHandler::new(
+            vec![
+                Box::new(CgiRequestHandler::new(
+                    String::from("cgi-bin"),
+                    String::from("cgi-bin"),
+                    StaticRequestHandler::new(String::from("public_html"), Vec::new()),
+                    CgiSettings::default(),
+                )),
+                Box::new(StaticRequestHandler::new(
+                    String::from("public_html"),
+                    Vec::new(),
+                )),
+            ],
+            ConnectionSettings::default(),
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/cgi-bin/bash_local_redirect.sh")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::CONTENT_LENGTH, "4")
+            .body(b"a=1\n".to_vec())
+            .unwrap();
+
+        let response = connection_handler.handle_request(request, &stream);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.body().len(),
+            Some(fs::metadata("public_html/index.html").unwrap().len())
+        );
     }
 }
@@ -1,3 +1,10 @@
+pub mod backend;
 pub mod cgi_request;
+pub mod fastcgi_request;
+pub mod proxy_request;
+#[allow(clippy::module_inception)]
 pub mod request;
+pub mod scgi_request;
 pub mod static_request;
+#[cfg(test)]
+pub mod test_support;
@@ -0,0 +1,137 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Address of an application server requests are forwarded to (such as a
+/// FastCGI or SCGI application).
+#[derive(Clone, Debug, Hash, PartialEq)]
+pub enum BackendAddress {
+    /// TCP address, such as `127.0.0.1:9000`.
+    Tcp(String),
+    /// Path to a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl BackendAddress {
+    /// Parses an address, which is either a TCP address (`host:port`) or the
+    /// path to a Unix domain socket prefixed with `unix:`.
+    ///
+    pub fn parse(address: &str) -> BackendAddress {
+        match address.strip_prefix("unix:") {
+            Some(path) => BackendAddress::Unix(PathBuf::from(path)),
+            None => BackendAddress::Tcp(address.to_string()),
+        }
+    }
+
+    /// Returns the value of the `Host` header for requests sent to this
+    /// address: the host and port of TCP addresses, or `localhost` for Unix
+    /// domain sockets.
+    ///
+    pub fn host(&self) -> &str {
+        match self {
+            BackendAddress::Tcp(address) => address,
+            BackendAddress::Unix(_) => "localhost",
+        }
+    }
+}
+
+/// Connection to an application server, over TCP or a Unix domain socket.
+/// The connection is shut down when dropped.
+pub enum BackendStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl BackendStream {
+    /// Connects to the given address. The timeout applies to the connection
+    /// itself, as well as to every later read and write.
+    ///
+    pub fn connect(address: &BackendAddress, timeout: Duration) -> io::Result<BackendStream> {
+        let stream = match address {
+            BackendAddress::Tcp(address) => {
+                let socket_address = address.to_socket_addrs()?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "backend address not found")
+                })?;
+                BackendStream::Tcp(TcpStream::connect_timeout(&socket_address, timeout)?)
+            }
+            BackendAddress::Unix(path) => BackendStream::Unix(UnixStream::connect(path)?),
+        };
+
+        match &stream {
+            BackendStream::Tcp(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+            }
+            BackendStream::Unix(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+impl Read for BackendStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BackendStream::Tcp(stream) => stream.read(buf),
+            BackendStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for BackendStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            BackendStream::Tcp(stream) => stream.write(buf),
+            BackendStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            BackendStream::Tcp(stream) => stream.flush(),
+            BackendStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Drop for BackendStream {
+    fn drop(&mut self) {
+        let _ = match self {
+            BackendStream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            BackendStream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+/// Helper function which checks whether an I/O error comes from a timeout.
+///
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Splits the given URI path into the script name (the path the application
+/// is served at, without its leading slash in `app_path`) and the extra path
+/// following it. Returns `None` if the path isn't under the application path.
+///
+pub fn split_path<'a>(app_path: &str, uri_path: &'a str) -> Option<(&'a str, &'a str)> {
+    if app_path.is_empty() {
+        return Some(("", uri_path));
+    }
+
+    let relative_path = uri_path.strip_prefix('/')?.strip_prefix(app_path)?;
+    if !relative_path.is_empty() && !relative_path.starts_with('/') {
+        return None;
+    }
+
+    Some(uri_path.split_at(uri_path.len() - relative_path.len()))
+}
@@ -1,3 +1,6 @@
+pub mod cgi_error;
+pub mod cgi_error_log;
 pub mod cgi_handler;
 pub mod cgi_metavariables;
+pub mod cgi_process;
 pub mod cgi_response;
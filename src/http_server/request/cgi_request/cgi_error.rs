@@ -0,0 +1,131 @@
+use std::{error::Error, fmt, io};
+
+use http::{header, HeaderValue, Response, StatusCode};
+
+use crate::http_server::response::ResponseBody;
+
+/// Reasons why a CGI program couldn't produce a valid response.
+#[derive(Debug)]
+pub enum CgiError {
+    /// The program couldn't be started.
+    SpawnFailed(io::Error),
+    /// The application server running the program couldn't be reached.
+    ConnectionFailed(io::Error),
+    /// The server isn't allowed to run the program (for instance because
+    /// the script isn't executable).
+    PermissionDenied(io::Error),
+    /// The header block of the program output isn't valid.
+    InvalidHeaderSyntax(String),
+    /// A header required by the type of response is missing.
+    MissingHeader(&'static str),
+    /// The `Status` header doesn't hold a valid status code for the type of
+    /// response.
+    BadStatus(String),
+    /// The program didn't send its headers before the timeout expired.
+    Timeout,
+    /// The program was stopped before sending its headers (for exceeding a
+    /// resource limit, for instance).
+    Stopped(String),
+}
+
+impl CgiError {
+    /// Returns the status code of the response sent to the client.
+    ///
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            CgiError::SpawnFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            CgiError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            CgiError::ConnectionFailed(_)
+            | CgiError::InvalidHeaderSyntax(_)
+            | CgiError::MissingHeader(_)
+            | CgiError::BadStatus(_)
+            | CgiError::Stopped(_) => StatusCode::BAD_GATEWAY,
+            CgiError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Generates the response sent to the client. The response body is empty
+    /// unless `detailed` is set, in which case it describes the error (which
+    /// is meant for development only, since it may expose details about the
+    /// server).
+    ///
+    pub fn to_response(&self, detailed: bool) -> Response<ResponseBody> {
+        let status = self.status_code();
+        let mut response = Response::new(ResponseBody::empty());
+        *response.status_mut() = status;
+
+        if detailed {
+            let reason = status.canonical_reason().unwrap_or("");
+            let page = format!("{} {}\n\nCGI error: {}\n", status.as_str(), reason, self);
+            *response.body_mut() = ResponseBody::from(page.into_bytes());
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+        }
+
+        response
+    }
+}
+
+impl fmt::Display for CgiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CgiError::SpawnFailed(error) => write!(f, "couldn't start the program: {error}"),
+            CgiError::ConnectionFailed(error) => {
+                write!(f, "couldn't reach the application server: {error}")
+            }
+            CgiError::PermissionDenied(error) => {
+                write!(f, "not allowed to run the program: {error}")
+            }
+            CgiError::InvalidHeaderSyntax(details) => write!(f, "invalid header block: {details}"),
+            CgiError::MissingHeader(header_name) => {
+                write!(f, "missing required header: {header_name}")
+            }
+            CgiError::BadStatus(status) => write!(f, "invalid status: {status:?}"),
+            CgiError::Timeout => write!(f, "timed out before sending the headers"),
+            CgiError::Stopped(reason) => {
+                write!(f, "stopped before sending the headers: {reason}")
+            }
+        }
+    }
+}
+
+impl Error for CgiError {}
+
+impl From<io::Error> for CgiError {
+    fn from(error: io::Error) -> CgiError {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => CgiError::PermissionDenied(error),
+            _ => CgiError::SpawnFailed(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_errors_are_mapped_to_status_codes() {
+        let permission_denied = CgiError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        let not_found = CgiError::from(io::Error::from(io::ErrorKind::NotFound));
+
+        assert_eq!(permission_denied.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(not_found.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn detailed_response_describes_the_error() {
+        let error = CgiError::MissingHeader("Content-Type");
+
+        assert!(error.to_response(false).body().is_empty());
+        match error.to_response(true).into_body() {
+            ResponseBody::Bytes(page) => assert_eq!(
+                String::from_utf8(page).unwrap(),
+                "502 Bad Gateway\n\nCGI error: missing required header: Content-Type\n"
+            ),
+            _ => panic!("Detailed error page should be in memory"),
+        }
+    }
+}
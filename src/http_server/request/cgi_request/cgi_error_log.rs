@@ -0,0 +1,145 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, log, Level};
+
+/// File to which the stderr output of CGI programs is appended. Once the file
+/// would grow past `max_size` bytes, it is renamed with a `.1` suffix
+/// (replacing the previous one) and a new file is started.
+pub struct CgiErrorLog {
+    path: PathBuf,
+    max_size: u64,
+    file: Mutex<Option<File>>,
+}
+
+impl CgiErrorLog {
+    pub fn new(path: PathBuf, max_size: u64) -> CgiErrorLog {
+        CgiErrorLog {
+            path,
+            max_size,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Returns the path the previous log file is moved to on rotation.
+    ///
+    fn rotated_path(&self) -> PathBuf {
+        let mut rotated_path = self.path.clone().into_os_string();
+        rotated_path.push(".1");
+        PathBuf::from(rotated_path)
+    }
+
+    /// Appends a line to the log file, rotating it first if needed.
+    ///
+    pub fn append(&self, line: &str) -> io::Result<()> {
+        let mut file = self
+            .file
+            .lock()
+            .expect("Failed to acquire the CGI error log mutex");
+
+        let current_size = match file.as_ref() {
+            None => fs::metadata(&self.path).map_or(0, |metadata| metadata.len()),
+            Some(file) => file.metadata()?.len(),
+        };
+        if current_size > 0 && current_size + line.len() as u64 + 1 > self.max_size {
+            drop(file.take());
+            fs::rename(&self.path, self.rotated_path())?;
+        }
+
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+
+        match file.as_mut() {
+            None => Ok(()),
+            Some(file) => writeln!(file, "{line}"),
+        }
+    }
+}
+
+/// Sends the stderr output of a CGI program to the server log, tagging each
+/// line with the script and the request it comes from, and optionally to a
+/// dedicated error log file.
+pub struct CgiStderrLogger {
+    pub script: String,
+    pub request_id: String,
+    pub client_address: String,
+    pub level: Level,
+    pub error_log: Option<Arc<CgiErrorLog>>,
+}
+
+impl CgiStderrLogger {
+    /// Reads the given stderr stream line by line from a separate thread
+    /// until it is closed.
+    ///
+    pub fn spawn<R: Read + Send + 'static>(self, stderr: R) {
+        thread::spawn(move || {
+            let mut stderr = BufReader::new(stderr);
+            let mut line = Vec::new();
+
+            loop {
+                line.clear();
+                match stderr.read_until(b'\n', &mut line) {
+                    Ok(0) => break,
+                    Err(error) => {
+                        debug!("Error reading CGI stderr: {:?}", error);
+                        break;
+                    }
+                    Ok(_) => self.log_line(String::from_utf8_lossy(&line).trim_end()),
+                }
+            }
+        });
+    }
+
+    /// Logs a single line of stderr output.
+    ///
+    pub fn log_line(&self, line: &str) {
+        let tags = format!(
+            "[{}] [request {}] [client {}]",
+            self.script, self.request_id, self.client_address
+        );
+        log!(self.level, "CGI stderr {}: {}", tags, line);
+
+        if let Some(error_log) = &self.error_log {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |timestamp| timestamp.as_secs());
+            if let Err(error) = error_log.append(&format!("[{timestamp}] {tags} {line}")) {
+                debug!("Error writing to the CGI error log: {:?}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_log_is_rotated_when_full() {
+        let path = std::env::temp_dir().join(format!("{}-cgi_error.log", std::process::id()));
+        let error_log = CgiErrorLog::new(path.clone(), 20);
+
+        error_log.append("first line").unwrap();
+        error_log.append("second line").unwrap();
+        error_log.append("third").unwrap();
+
+        let rotated_path = error_log.rotated_path();
+        assert_eq!(fs::read_to_string(&rotated_path).unwrap(), "first line\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second line\nthird\n");
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(rotated_path).unwrap();
+    }
+}
@@ -1,35 +1,106 @@
 use std::{
+    collections::HashMap,
     fs,
-    io::Write,
     net::TcpStream,
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    sync::Arc,
+    time::Duration,
 };
 
 use http::{header, HeaderName, Request, Response, StatusCode};
 
-use log::debug;
+use log::{debug, warn, Level};
 
 use crate::http_server::{
     request::{
         cgi_request::{
+            cgi_error::CgiError,
+            cgi_error_log::{CgiErrorLog, CgiStderrLogger},
             cgi_metavariables::CGIMetavariableMap,
-            cgi_response::{convert_cgi_response_to_http, parse_cgi_response},
+            cgi_process::{run_process, CgiProgram, CgiResourceLimits},
+            cgi_response::{convert_cgi_response_to_http, read_cgi_response},
         },
-        request::RequestHandler,
+        request::{RequestHandler, RequestId},
         static_request::static_handler::StaticRequestHandler,
     },
-    response::generate_error_response,
+    response::{generate_error_response, ResponseBody},
 };
 
 use super::cgi_metavariables::CGIMetavariable;
 
 const DEFAULT_PORT: &str = "80";
+const NPH_SCRIPT_PREFIX: &str = "nph-";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tunable options for the execution of CGI programs.
+pub struct CgiSettings {
+    /// Maximum wall-clock time a CGI program may run for. Programs running
+    /// for longer are killed along with their whole process group.
+    pub timeout: Duration,
+    /// Resource limits applied to each CGI program.
+    pub resource_limits: CgiResourceLimits,
+    /// Maximum number of bytes read from the output of a CGI program.
+    /// Programs producing more output are killed.
+    pub max_output_size: Option<u64>,
+    /// Request headers which are not passed to CGI programs as `HTTP_*`
+    /// metavariables. By default these are the headers whose information is
+    /// already available through other metavariables (`Authorization`,
+    /// `Content-Length` and `Content-Type`), as well as `Proxy`, whose
+    /// `HTTP_PROXY` variable would be mistaken for a proxy setting by many
+    /// HTTP libraries.
+    pub excluded_headers: Vec<HeaderName>,
+    /// Level at which the stderr output of CGI programs is logged.
+    pub stderr_log_level: Level,
+    /// File to which the stderr output of CGI programs is also appended, if
+    /// any.
+    pub error_log: Option<Arc<CgiErrorLog>>,
+    /// Interpreters used to run scripts, by file extension (without the
+    /// leading dot, e.g. `py` -> `python3`). Scripts with a mapped extension
+    /// don't need to be executable, while other ones are executed directly.
+    pub interpreters: HashMap<String, String>,
+    /// When set, only scripts with a mapped extension are run, and any other
+    /// request is left to the next handlers (like Apache's `AddHandler`).
+    /// This allows scripts to live among static files.
+    pub add_handler: bool,
+    /// Whether error responses describe what went wrong with the CGI
+    /// program. Meant for development only, since it may expose details
+    /// about the server.
+    pub detailed_errors: bool,
+}
+
+/// Returns the request headers which aren't passed as `HTTP_*` metavariables
+/// by default (see `CgiSettings::excluded_headers`).
+///
+pub fn default_excluded_headers() -> Vec<HeaderName> {
+    vec![
+        header::AUTHORIZATION,
+        header::CONTENT_LENGTH,
+        header::CONTENT_TYPE,
+        HeaderName::from_static("proxy"),
+    ]
+}
+
+impl Default for CgiSettings {
+    fn default() -> CgiSettings {
+        CgiSettings {
+            timeout: DEFAULT_TIMEOUT,
+            resource_limits: CgiResourceLimits::default(),
+            max_output_size: None,
+            excluded_headers: default_excluded_headers(),
+            stderr_log_level: Level::Warn,
+            error_log: None,
+            interpreters: HashMap::new(),
+            add_handler: false,
+            detailed_errors: false,
+        }
+    }
+}
 
 pub struct CgiRequestHandler {
     cgi_path: String,
     cgi_folder: String,
     static_handler: StaticRequestHandler,
+    settings: CgiSettings,
 }
 
 impl CgiRequestHandler {
@@ -37,179 +108,393 @@ impl CgiRequestHandler {
         cgi_path: String,
         cgi_folder: String,
         static_handler: StaticRequestHandler,
+        settings: CgiSettings,
     ) -> CgiRequestHandler {
         CgiRequestHandler {
             cgi_path,
             cgi_folder,
             static_handler,
+            settings,
         }
     }
 }
 
+/// Location of the CGI script targeted by a request, split according to
+/// section 3.3 of the CGI RFC: the script itself and the extra path
+/// following it.
+#[derive(Debug, PartialEq)]
+struct ScriptLocation {
+    /// Absolute path to the script file.
+    script_path: PathBuf,
+    /// Part of the URI path leading to the script (`SCRIPT_NAME`).
+    script_name: String,
+    /// Part of the URI path following the script (`PATH_INFO`).
+    path_info: String,
+}
+
 /// Helper function which returns the value of an HTTP request header if it is
 /// present. Otherwise returns an empty string.
 ///
-fn get_header_or_empty_string(request: &Request<String>, header_name: HeaderName) -> String {
+fn get_header_or_empty_string(request: &Request<Vec<u8>>, header_name: HeaderName) -> String {
     request
         .headers()
         .get(header_name)
         .map_or(String::from(""), |h| h.to_str().unwrap_or("").to_string())
 }
 
-/// Runs the CGI program located at `script_path` with the given `input_data`,
-/// setting up the supplied environment variables. Returns the CGI program
-/// output if successful. Otherwise returns the error.
+/// Helper function which joins all the values of an HTTP request header into
+/// a single string, as required for the protocol-specific metavariables.
+/// Values are separated by commas, except for cookies which use semicolons.
 ///
-fn run_process(
-    script_path: PathBuf,
-    input_data: &String,
-    env_variables: CGIMetavariableMap,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut parent_folder = script_path.clone();
-    parent_folder.pop();
-    let mut script_process = Command::new(script_path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .env_clear()
-        .current_dir(parent_folder)
-        .envs(&env_variables)
-        .spawn()?;
-
-    let mut stdin = script_process
-        .stdin
-        .take()
-        .ok_or("Error getting stdin for child process")?;
-    stdin.write_all(input_data.as_bytes())?;
-    drop(stdin);
-
-    let output_handle = script_process.wait_with_output()?;
-    let output = String::from_utf8_lossy(&output_handle.stdout);
-
-    Ok(output.to_string())
+fn join_header_values(request: &Request<Vec<u8>>, header_name: &HeaderName) -> String {
+    let separator = if header_name == header::COOKIE {
+        "; "
+    } else {
+        ", "
+    };
+
+    request
+        .headers()
+        .get_all(header_name)
+        .iter()
+        .map(|value| String::from_utf8_lossy(value.as_bytes()))
+        .collect::<Vec<_>>()
+        .join(separator)
 }
 
-impl CgiRequestHandler {
-    /// Creates a CGI metavariable map to be sent to the CGI program via
-    /// environment variables. The data in the map is extracted from the
-    /// incoming TCP stream an HTTP request.
-    ///
-    fn generate_environment_variables(
-        &self,
-        stream: &TcpStream,
-        request: &Request<String>,
-    ) -> CGIMetavariableMap {
-        let mut metavariables = CGIMetavariableMap::new();
+/// Creates the metavariables which don't depend on the request, describing
+/// the server itself. These are also the environment of the FastCGI
+/// application processes started by the server.
+///
+pub fn server_environment_variables() -> CGIMetavariableMap {
+    let mut metavariables = CGIMetavariableMap::new();
 
-        let authorization_data = get_header_or_empty_string(request, header::AUTHORIZATION);
-        let authorization_pair = authorization_data.split_once(" ");
+    metavariables.insert(CGIMetavariable::GatewayInterface, String::from("CGI/1.1"));
+    metavariables.insert(
+        CGIMetavariable::ServerSoftware,
+        String::from("Rust Web CGI/0.0.1"),
+    );
 
-        match authorization_pair {
-            Some((scheme, parameters)) => {
-                metavariables.insert(CGIMetavariable::AuthType, scheme.to_string());
-                metavariables.insert(CGIMetavariable::RemoteUser, parameters.to_string());
-            }
-            None => {
-                metavariables.insert(CGIMetavariable::AuthType, String::from(""));
-            }
+    metavariables
+}
+
+/// Creates a CGI metavariable map to be sent to the CGI program via
+/// environment variables. The data in the map is extracted from the
+/// incoming TCP stream an HTTP request, along with the script name and extra
+/// path the request URI was split into. The extra path is mapped through
+/// `document_root` for `PATH_TRANSLATED`, and request headers are passed as
+/// `HTTP_*` metavariables unless they are part of `excluded_headers`. Headers
+/// whose name contains an underscore are dropped, since they would map to
+/// the same metavariable as their dashed form (letting a client override a
+/// header set by a proxy in front of the server).
+///
+pub fn generate_environment_variables(
+    stream: &TcpStream,
+    request: &Request<Vec<u8>>,
+    script_name: &str,
+    path_info: &str,
+    document_root: &str,
+    excluded_headers: &[HeaderName],
+) -> CGIMetavariableMap {
+    let mut metavariables = server_environment_variables();
+
+    let authorization_data = get_header_or_empty_string(request, header::AUTHORIZATION);
+    let authorization_pair = authorization_data.split_once(" ");
+
+    match authorization_pair {
+        Some((scheme, parameters)) => {
+            metavariables.insert(CGIMetavariable::AuthType, scheme.to_string());
+            metavariables.insert(CGIMetavariable::RemoteUser, parameters.to_string());
+        }
+        None => {
+            metavariables.insert(CGIMetavariable::AuthType, String::from(""));
         }
+    }
 
-        let content_length = request.body().len();
+    let content_length = request.body().len();
+    metavariables.insert(
+        CGIMetavariable::ContentLength,
+        if content_length > 0 {
+            content_length.to_string()
+        } else {
+            String::from("")
+        },
+    );
+
+    let content_type = get_header_or_empty_string(request, header::CONTENT_TYPE);
+    if !content_type.is_empty() {
+        metavariables.insert(CGIMetavariable::ContentType, content_type);
+    } else {
         metavariables.insert(
-            CGIMetavariable::ContentLength,
-            if content_length > 0 {
-                content_length.to_string()
-            } else {
-                String::from("")
-            },
+            CGIMetavariable::ContentType,
+            String::from("application/octet-stream"),
         );
+    }
 
-        let content_type = get_header_or_empty_string(request, header::CONTENT_TYPE);
-        if content_type != "" {
-            metavariables.insert(CGIMetavariable::ContentType, content_type);
-        } else {
-            metavariables.insert(
-                CGIMetavariable::ContentType,
-                String::from("application/octet-stream"),
-            );
+    let path_translated = if path_info.is_empty() {
+        String::from("")
+    } else {
+        fs::canonicalize(document_root)
+            .map(|static_folder| {
+                static_folder
+                    .join(path_info.trim_start_matches('/'))
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .unwrap_or_default()
+    };
+    metavariables.insert(CGIMetavariable::PathInfo, path_info.to_string());
+    metavariables.insert(CGIMetavariable::PathTranslated, path_translated);
+
+    metavariables.insert(
+        CGIMetavariable::QueryString,
+        request.uri().query().unwrap_or("").to_string(),
+    );
+
+    let remote_addr = stream
+        .peer_addr()
+        .map_or(String::from(""), |addr| addr.ip().to_string());
+    metavariables.insert(CGIMetavariable::RemoteAddr, remote_addr.clone());
+    metavariables.insert(CGIMetavariable::RemoteHost, remote_addr);
+    metavariables.insert(CGIMetavariable::RemoteIdent, String::from(""));
+
+    metavariables.insert(CGIMetavariable::RequestMethod, request.method().to_string());
+
+    metavariables.insert(CGIMetavariable::ScriptName, script_name.to_string());
+
+    let host_value = request
+        .headers()
+        .get(header::HOST)
+        .map_or(String::from(""), |host| {
+            host.to_str().unwrap_or("").to_string()
+        });
+    let (server_name, server_port) = match host_value.split_once(":") {
+        Some((server_name, server_port)) => (server_name.to_string(), server_port.to_string()),
+        None => (host_value, DEFAULT_PORT.to_string()),
+    };
+    metavariables.insert(CGIMetavariable::ServerName, server_name);
+    metavariables.insert(CGIMetavariable::ServerPort, server_port);
+
+    metavariables.insert(CGIMetavariable::ServerProtocol, String::from("HTTP/1.0"));
+
+    for header_name in request.headers().keys() {
+        if excluded_headers.contains(header_name) {
+            continue;
         }
 
-        metavariables.insert(CGIMetavariable::GatewayInterface, String::from("CGI/1.1"));
-
-        metavariables.insert(CGIMetavariable::PathInfo, String::from(""));
-        metavariables.insert(CGIMetavariable::PathTranslated, String::from(""));
+        if header_name.as_str().contains('_') {
+            debug!("Ignoring header with an underscore: {:?}", header_name);
+            continue;
+        }
 
         metavariables.insert(
-            CGIMetavariable::QueryString,
-            request.uri().query().unwrap_or("").to_string(),
+            CGIMetavariable::from_header_name(header_name),
+            join_header_values(request, header_name),
         );
+    }
 
-        let remote_addr = stream
-            .peer_addr()
-            .map_or(String::from(""), |addr| addr.ip().to_string());
-        metavariables.insert(CGIMetavariable::RemoteAddr, remote_addr.clone());
-        metavariables.insert(CGIMetavariable::RemoteHost, remote_addr);
-        metavariables.insert(CGIMetavariable::RemoteIdent, String::from(""));
-
-        metavariables.insert(CGIMetavariable::RequestMethod, request.method().to_string());
+    metavariables
+}
 
-        metavariables.insert(
-            CGIMetavariable::ScriptName,
-            request.uri().path().to_string(),
-        );
+/// Returns the metavariables expected by interpreters run as CGI programs:
+/// `SCRIPT_FILENAME`, which tells them the script to run (php-cgi looks for
+/// it there rather than in its arguments), and `REDIRECT_STATUS`, without
+/// which php-cgi refuses to run (`cgi.force_redirect`).
+///
+fn interpreter_environment_variables(script_path: &Path) -> CGIMetavariableMap {
+    CGIMetavariableMap::from([
+        (
+            CGIMetavariable::ProtocolSpecific(String::from("SCRIPT_FILENAME")),
+            script_path.to_string_lossy().to_string(),
+        ),
+        (
+            CGIMetavariable::ProtocolSpecific(String::from("REDIRECT_STATUS")),
+            String::from("200"),
+        ),
+    ])
+}
 
-        let host_value = request
-            .headers()
-            .get(header::HOST)
-            .map_or(String::from(""), |host| {
-                host.to_str().unwrap_or("").to_string()
-            });
-        let (server_name, server_port) = match host_value.split_once(":") {
-            Some((server_name, server_port)) => (server_name.to_string(), server_port.to_string()),
-            None => (host_value, DEFAULT_PORT.to_string()),
+impl CgiRequestHandler {
+    /// Finds the CGI script targeted by the given URI path, following section
+    /// 3.3 of the CGI RFC: the path segments are walked from the CGI folder
+    /// until a file is found, and the remaining segments are kept as the
+    /// extra path information. Returns `None` if the path isn't under the
+    /// expected CGI path, or a NOT FOUND status code if no script matches (in
+    /// AddHandler mode, `None` is returned instead, as it is for files without
+    /// a mapped interpreter).
+    ///
+    /// # Panics
+    ///
+    /// The `resolve_script` method panics if the `CGI_FOLDER` path does not
+    /// exist.
+    ///
+    fn resolve_script(&self, uri_path: &str) -> Option<Result<ScriptLocation, StatusCode>> {
+        let relative_path = uri_path.strip_prefix('/')?.strip_prefix(&self.cgi_path)?;
+        if !self.cgi_path.is_empty() && !relative_path.is_empty() && !relative_path.starts_with('/')
+        {
+            return None;
+        }
+        // Requests which don't target a script are left to the next handlers
+        // in AddHandler mode.
+        let not_found = if self.settings.add_handler {
+            None
+        } else {
+            Some(Err(StatusCode::NOT_FOUND))
         };
-        metavariables.insert(CGIMetavariable::ServerName, server_name);
-        metavariables.insert(CGIMetavariable::ServerPort, server_port);
+        let script_name_start = uri_path.len() - relative_path.len();
+        let relative_path = relative_path.trim_start_matches('/');
+        let relative_start = uri_path.len() - relative_path.len();
 
-        metavariables.insert(CGIMetavariable::ServerProtocol, String::from("HTTP/1.0"));
+        let folder_path = fs::canonicalize(&self.cgi_folder).expect("CGI folder does not exist");
+        let segment_ends = relative_path
+            .match_indices('/')
+            .map(|(index, _)| index)
+            .chain(std::iter::once(relative_path.len()));
+
+        for segment_end in segment_ends {
+            let candidate = &relative_path[..segment_end];
+            if candidate.is_empty() || candidate.ends_with('/') {
+                continue;
+            }
 
-        metavariables.insert(
-            CGIMetavariable::ServerSoftware,
-            String::from("Rust Web CGI/0.0.1"),
-        );
+            debug!("Looking for CGI script at {}", candidate);
+            let candidate_path = match fs::canonicalize(folder_path.join(candidate)) {
+                Err(_) => break,
+                Ok(path) => path,
+            };
+
+            if !candidate_path.starts_with(&folder_path) {
+                break;
+            }
+
+            if candidate_path.is_file() {
+                if self.settings.add_handler && self.interpreter_for(&candidate_path).is_none() {
+                    return None;
+                }
+
+                let script_end = relative_start + segment_end;
+                debug!("CGI script to be loaded: {:?}", candidate_path);
+                return Some(Ok(ScriptLocation {
+                    script_path: candidate_path,
+                    script_name: uri_path[..script_end].to_string(),
+                    path_info: uri_path[script_end..].to_string(),
+                }));
+            }
+        }
+
+        debug!("No CGI script found for {}", &uri_path[script_name_start..]);
+        not_found
+    }
 
-        return metavariables;
+    /// Returns the interpreter mapped to the extension of the given script,
+    /// if any.
+    ///
+    fn interpreter_for(&self, script_path: &Path) -> Option<&String> {
+        let extension = script_path.extension()?.to_str()?;
+        self.settings.interpreters.get(extension)
     }
 
     /// Orchestrates the whole execution of the CGI program: sets the
     /// environment, runs the code, parses the response and generates the
     /// proper HTTP response
     ///
+    /// Non-parsed header scripts (whose name starts with `nph-`) write the
+    /// whole HTTP response themselves, so their output is passed to the
+    /// client as it is, without being parsed.
+    ///
+    /// Errors are logged and turned into the matching error response.
+    ///
     fn run_cgi_script(
         &self,
         stream: &TcpStream,
-        request: &Request<String>,
-        script_path: PathBuf,
-    ) -> Response<String> {
-        let envs = self.generate_environment_variables(stream, request);
-
-        match run_process(script_path, request.body(), envs) {
-            Err(_) => generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
-            Ok(output) => {
-                debug!("CGI output: {}", output);
-                let cgi_response = parse_cgi_response(output);
-
-                match cgi_response {
-                    Err(_) => generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
-                    Ok(cgi_response) => {
-                        convert_cgi_response_to_http(stream, &self.static_handler, cgi_response)
-                    }
-                }
+        request: &Request<Vec<u8>>,
+        location: ScriptLocation,
+    ) -> Response<ResponseBody> {
+        let script_name = location.script_name.clone();
+
+        match self.execute_cgi_script(stream, request, location) {
+            Err(error) => {
+                warn!("CGI script {} failed: {}", script_name, error);
+                debug!("CGI error: {:?}", error);
+                error.to_response(self.settings.detailed_errors)
             }
+            Ok(response) => response,
         }
     }
+
+    /// Runs the CGI program and converts its output into an HTTP response.
+    ///
+    fn execute_cgi_script(
+        &self,
+        stream: &TcpStream,
+        request: &Request<Vec<u8>>,
+        location: ScriptLocation,
+    ) -> Result<Response<ResponseBody>, CgiError> {
+        let mut envs = generate_environment_variables(
+            stream,
+            request,
+            &location.script_name,
+            &location.path_info,
+            self.static_handler.static_folder(),
+            &self.settings.excluded_headers,
+        );
+        let interpreter = self.interpreter_for(&location.script_path).cloned();
+        if interpreter.is_some() {
+            envs.extend(interpreter_environment_variables(&location.script_path));
+        }
+        let is_nph = location
+            .script_path
+            .file_name()
+            .is_some_and(|file_name| file_name.to_string_lossy().starts_with(NPH_SCRIPT_PREFIX));
+
+        let stderr_logger = CgiStderrLogger {
+            script: location.script_path.to_string_lossy().to_string(),
+            request_id: request
+                .extensions()
+                .get::<RequestId>()
+                .map_or(String::from("-"), |request_id| request_id.to_string()),
+            client_address: stream
+                .peer_addr()
+                .map_or(String::from("-"), |addr| addr.to_string()),
+            level: self.settings.stderr_log_level,
+            error_log: self.settings.error_log.clone(),
+        };
+
+        let program = CgiProgram {
+            interpreter,
+            script_path: location.script_path,
+        };
+        debug!("CGI program: {:?}", program);
+
+        let output = run_process(
+            program,
+            request.body(),
+            envs,
+            self.settings.timeout,
+            self.settings.resource_limits,
+            self.settings.max_output_size,
+            stderr_logger,
+        );
+
+        let output = output?;
+        if is_nph {
+            debug!("Passing the output of the NPH script to the client");
+            return Ok(Response::new(ResponseBody::raw(output)));
+        }
+
+        let status = output.status();
+        let cgi_response = match read_cgi_response(output) {
+            Err(_) if status.timed_out() => return Err(CgiError::Timeout),
+            Err(error) => return Err(status.failure_reason().map_or(error, CgiError::Stopped)),
+            Ok(cgi_response) => cgi_response,
+        };
+
+        debug!("CGI headers: {:?}", cgi_response.headers());
+        convert_cgi_response_to_http(cgi_response)
+    }
 }
 
-impl RequestHandler<String> for CgiRequestHandler {
+impl RequestHandler<Vec<u8>> for CgiRequestHandler {
     /// Handles an incoming request. If the requested path matches the
     /// expected CGI path, runs the CGI script and returns a response.
     /// Otherwise returns a `None` value so that the next handler can try to
@@ -223,30 +508,149 @@ impl RequestHandler<String> for CgiRequestHandler {
     fn handle_request(
         &self,
         stream: &TcpStream,
-        request: &Request<String>,
-    ) -> Option<Response<String>> {
-        let uri_path = request.uri().path();
-        let file_path = &uri_path[1..].strip_prefix(&self.cgi_path)?;
-        let file_path = file_path.strip_prefix("/").unwrap_or(file_path);
-
-        debug!("CGI script to be loaded: {}", file_path);
-        let folder_path = fs::canonicalize(&self.cgi_folder).expect("CGI folder does not exist");
-        let file_path = fs::canonicalize(Path::new(&self.cgi_folder).join(file_path));
-        let abs_file_path = match file_path {
-            Err(_) => {
-                debug!("No CGI script found");
-                return Some(generate_error_response(StatusCode::NOT_FOUND));
-            }
-            Ok(path) => {
-                if path.starts_with(folder_path) {
-                    path
-                } else {
-                    return Some(generate_error_response(StatusCode::NOT_FOUND));
-                }
-            }
+        request: &Request<Vec<u8>>,
+    ) -> Option<Response<ResponseBody>> {
+        let location = match self.resolve_script(request.uri().path())? {
+            Err(status) => return Some(generate_error_response(status)),
+            Ok(location) => location,
         };
 
-        debug!("Searching for {:?}", abs_file_path);
-        Some(self.run_cgi_script(stream, request, abs_file_path))
+        debug!("Running {:?}", location);
+        Some(self.run_cgi_script(stream, request, location))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    fn sample_handler() -> CgiRequestHandler {
+        CgiRequestHandler::new(
+            String::from("cgi-bin"),
+            String::from("cgi-bin"),
+            StaticRequestHandler::new(String::from("public_html"), Vec::new()),
+            CgiSettings::default(),
+        )
+    }
+
+    #[test]
+    fn extra_path_is_split_from_script() {
+        let location = sample_handler()
+            .resolve_script("/cgi-bin/bash_document.sh/users/42")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            location.script_path,
+            fs::canonicalize("cgi-bin/bash_document.sh").unwrap()
+        );
+        assert_eq!(location.script_name, "/cgi-bin/bash_document.sh");
+        assert_eq!(location.path_info, "/users/42");
+    }
+
+    #[test]
+    fn script_without_extra_path_has_empty_path_info() {
+        let location = sample_handler()
+            .resolve_script("/cgi-bin/bash_document.sh")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(location.script_name, "/cgi-bin/bash_document.sh");
+        assert_eq!(location.path_info, "");
+    }
+
+    #[test]
+    fn request_headers_are_joined_into_metavariables() {
+        let request = Request::builder()
+            .header("user-agent", "curl/8.0")
+            .header("accept", "text/html")
+            .header("accept", "text/plain")
+            .header("cookie", "a=1")
+            .header("cookie", "b=2")
+            .body(Vec::new())
+            .unwrap();
+
+        assert_eq!(
+            join_header_values(&request, &header::ACCEPT),
+            "text/html, text/plain"
+        );
+        assert_eq!(join_header_values(&request, &header::COOKIE), "a=1; b=2");
+        assert_eq!(
+            CGIMetavariable::from_header_name(&header::USER_AGENT).to_string(),
+            "HTTP_USER_AGENT"
+        );
+    }
+
+    #[test]
+    fn headers_with_underscores_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let request = Request::builder()
+            .header("x-forwarded-user", "proxy")
+            .header("x_forwarded_user", "client")
+            .body(Vec::new())
+            .unwrap();
+
+        let metavariables =
+            generate_environment_variables(&stream, &request, "/script", "", "public_html", &[]);
+
+        assert_eq!(
+            metavariables
+                [&CGIMetavariable::from_header_name(&HeaderName::from_static("x-forwarded-user"))],
+            "proxy"
+        );
+    }
+
+    #[test]
+    fn add_handler_mode_only_runs_mapped_scripts() {
+        let handler = CgiRequestHandler::new(
+            String::from(""),
+            String::from("public_html"),
+            StaticRequestHandler::new(String::from("public_html"), Vec::new()),
+            CgiSettings {
+                interpreters: HashMap::from([(String::from("py"), String::from("python3"))]),
+                add_handler: true,
+                ..CgiSettings::default()
+            },
+        );
+
+        let location = handler.resolve_script("/hello.py/world").unwrap().unwrap();
+        assert_eq!(location.script_name, "/hello.py");
+        assert_eq!(location.path_info, "/world");
+        assert_eq!(
+            handler.interpreter_for(&location.script_path).unwrap(),
+            "python3"
+        );
+        let envs = interpreter_environment_variables(&location.script_path);
+        assert_eq!(
+            envs[&CGIMetavariable::ProtocolSpecific(String::from("SCRIPT_FILENAME"))],
+            fs::canonicalize("public_html/hello.py")
+                .unwrap()
+                .to_string_lossy()
+        );
+        assert_eq!(
+            envs[&CGIMetavariable::ProtocolSpecific(String::from("REDIRECT_STATUS"))],
+            "200"
+        );
+
+        assert_eq!(handler.resolve_script("/index.html"), None);
+        assert_eq!(handler.resolve_script("/missing.py"), None);
+    }
+
+    #[test]
+    fn missing_script_is_not_found() {
+        let handler = sample_handler();
+
+        assert_eq!(
+            handler.resolve_script("/cgi-bin/missing.sh/users"),
+            Some(Err(StatusCode::NOT_FOUND))
+        );
+        assert_eq!(
+            handler.resolve_script("/cgi-bin/../src/main.rs"),
+            Some(Err(StatusCode::NOT_FOUND))
+        );
+        assert_eq!(handler.resolve_script("/cgi-binary/test.sh"), None);
     }
 }
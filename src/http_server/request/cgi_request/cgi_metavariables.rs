@@ -1,8 +1,8 @@
-use std::{collections::HashMap, ffi::OsStr};
+use std::{collections::HashMap, ffi::OsStr, fmt};
 
-#[derive(
-    strum_macros::Display, strum_macros::IntoStaticStr, strum_macros::AsRefStr, Eq, Hash, PartialEq,
-)]
+use http::HeaderName;
+
+#[derive(strum_macros::IntoStaticStr, Debug, Eq, Hash, PartialEq)]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum CGIMetavariable {
     AuthType,
@@ -22,12 +22,40 @@ pub enum CGIMetavariable {
     ServerPort,
     ServerProtocol,
     ServerSoftware,
+    /// Protocol-specific metavariable (section 4.1.18 of the CGI RFC), holding
+    /// its full name, such as `HTTP_USER_AGENT`.
+    ProtocolSpecific(String),
+}
+
+impl CGIMetavariable {
+    /// Creates the protocol-specific metavariable carrying the value of the
+    /// given HTTP request header: the header name is upper-cased, its dashes
+    /// are replaced by underscores and it is prefixed with `HTTP_`.
+    ///
+    pub fn from_header_name(header_name: &HeaderName) -> CGIMetavariable {
+        let name = header_name.as_str().to_ascii_uppercase().replace('-', "_");
+        CGIMetavariable::ProtocolSpecific(format!("HTTP_{name}"))
+    }
+
+    /// Returns the name of the metavariable, as seen by the CGI program.
+    ///
+    pub fn name(&self) -> &str {
+        match self {
+            CGIMetavariable::ProtocolSpecific(name) => name,
+            metavariable => metavariable.into(),
+        }
+    }
+}
+
+impl fmt::Display for CGIMetavariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 impl AsRef<OsStr> for CGIMetavariable {
     fn as_ref(&self) -> &OsStr {
-        let a: &str = self.as_ref();
-        OsStr::new(a)
+        OsStr::new(self.name())
     }
 }
 
@@ -0,0 +1,494 @@
+use std::{
+    io::{self, Read, Write},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::http_server::request::cgi_request::{
+    cgi_error::CgiError, cgi_error_log::CgiStderrLogger, cgi_metavariables::CGIMetavariableMap,
+};
+
+/// Resource limits applied to each CGI program before it starts running.
+/// Limits set to `None` are inherited from the server process.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CgiResourceLimits {
+    /// Maximum CPU time, in seconds (`RLIMIT_CPU`).
+    pub cpu_seconds: Option<u64>,
+    /// Maximum size of the virtual memory, in bytes (`RLIMIT_AS`).
+    pub address_space: Option<u64>,
+    /// Maximum number of open file descriptors (`RLIMIT_NOFILE`).
+    pub open_files: Option<u64>,
+    /// Maximum number of processes for the user running the server
+    /// (`RLIMIT_NPROC`).
+    pub processes: Option<u64>,
+    /// Maximum size of a file written by the program, in bytes
+    /// (`RLIMIT_FSIZE`).
+    pub file_size: Option<u64>,
+}
+
+impl CgiResourceLimits {
+    /// Applies the limits to the current process. Meant to be called in the
+    /// child process right before the CGI program is executed, so it only
+    /// calls async-signal-safe functions.
+    ///
+    fn apply(&self) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu_seconds),
+            (libc::RLIMIT_AS, self.address_space),
+            (libc::RLIMIT_NOFILE, self.open_files),
+            (libc::RLIMIT_NPROC, self.processes),
+            (libc::RLIMIT_FSIZE, self.file_size),
+        ];
+
+        for (resource, limit) in limits {
+            if let Some(limit) = limit {
+                // The hard CPU limit is one second past the soft one, so that
+                // programs get a SIGXCPU (which tells why they were stopped)
+                // before being killed.
+                let hard_limit = if resource == libc::RLIMIT_CPU {
+                    limit.saturating_add(1)
+                } else {
+                    limit
+                };
+                let limit = libc::rlimit {
+                    rlim_cur: limit as libc::rlim_t,
+                    rlim_max: hard_limit as libc::rlim_t,
+                };
+
+                // SAFETY: `setrlimit` only reads the given struct, which
+                // outlives the call.
+                if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// CGI program to be run: either a script which is executed directly, or a
+/// script which is passed to an interpreter (such as `python3`). The
+/// interpreter command may include arguments, separated by whitespace.
+#[derive(Debug, PartialEq)]
+pub struct CgiProgram {
+    pub script_path: PathBuf,
+    pub interpreter: Option<String>,
+}
+
+impl CgiProgram {
+    /// Builds the command which runs the program.
+    ///
+    fn command(&self) -> Command {
+        let interpreter = self
+            .interpreter
+            .as_deref()
+            .map(|interpreter| interpreter.split_whitespace().collect::<Vec<_>>());
+
+        match interpreter.as_deref() {
+            Some([program, arguments @ ..]) => {
+                let mut command = Command::new(program);
+                command.args(arguments).arg(&self.script_path);
+                command
+            }
+            _ => Command::new(&self.script_path),
+        }
+    }
+
+    /// Builds the command which runs the program from the folder holding the
+    /// script, with only the given environment variables and with the given
+    /// resource limits applied before it starts.
+    ///
+    pub fn prepare_command(
+        &self,
+        env_variables: &CGIMetavariableMap,
+        resource_limits: CgiResourceLimits,
+    ) -> Command {
+        let mut parent_folder = self.script_path.clone();
+        parent_folder.pop();
+        let mut command = self.command();
+        command
+            .env_clear()
+            .current_dir(parent_folder)
+            .envs(env_variables);
+
+        // SAFETY: the closure runs in the forked child and only calls
+        // `setrlimit`, which is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || resource_limits.apply());
+        }
+
+        command
+    }
+}
+
+/// Information on how a CGI program ended, shared between the code reading
+/// its output and the code deciding which response to send.
+#[derive(Debug, Default)]
+pub struct CgiProcessStatus {
+    timed_out: AtomicBool,
+    output_limit_exceeded: AtomicBool,
+    exit_status: Mutex<Option<ExitStatus>>,
+}
+
+impl CgiProcessStatus {
+    /// Returns whether the program was killed for exceeding its timeout.
+    ///
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether the program was killed for producing more output
+    /// than allowed.
+    ///
+    pub fn output_limit_exceeded(&self) -> bool {
+        self.output_limit_exceeded.load(Ordering::SeqCst)
+    }
+
+    /// Returns the exit status of the program, once it has been reaped.
+    ///
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        *self
+            .exit_status
+            .lock()
+            .expect("Failed to acquire the CGI status mutex")
+    }
+
+    /// Describes why the program was stopped by the server or by the system,
+    /// if it didn't end on its own.
+    ///
+    pub fn failure_reason(&self) -> Option<String> {
+        if self.timed_out() {
+            return Some(String::from("timeout exceeded"));
+        }
+
+        if self.output_limit_exceeded() {
+            return Some(String::from("output size limit exceeded"));
+        }
+
+        match self.exit_status()?.signal()? {
+            libc::SIGXCPU => Some(String::from("CPU time limit exceeded")),
+            libc::SIGXFSZ => Some(String::from("file size limit exceeded")),
+            signal => Some(format!("killed by signal {signal}")),
+        }
+    }
+}
+
+/// Kills the whole process group led by the given process.
+///
+fn kill_process_group(process_id: u32) {
+    // A negative id targets the whole process group.
+    // SAFETY: `kill` has no memory safety requirements.
+    unsafe {
+        libc::kill(-(process_id as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+/// Kills the whole process group of a CGI program if it is still running
+/// once the timeout expires. The watchdog is cancelled when dropped.
+struct Watchdog {
+    cancel_sender: Option<mpsc::Sender<()>>,
+}
+
+impl Watchdog {
+    fn new(process_id: u32, timeout: Duration, status: Arc<CgiProcessStatus>) -> Watchdog {
+        let (cancel_sender, cancel_receiver) = mpsc::channel::<()>();
+
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = cancel_receiver.recv_timeout(timeout) {
+                warn!(
+                    "CGI process {} exceeded the {:?} timeout, killing it",
+                    process_id, timeout
+                );
+                status.timed_out.store(true, Ordering::SeqCst);
+                kill_process_group(process_id);
+            }
+        });
+
+        Watchdog {
+            cancel_sender: Some(cancel_sender),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        drop(self.cancel_sender.take());
+    }
+}
+
+/// Output of a running CGI program. Reading from it reads the program
+/// stdout as it is produced. The program is reaped once the output is
+/// dropped.
+pub struct CgiProcessOutput {
+    child: Child,
+    status: Arc<CgiProcessStatus>,
+    max_output_size: Option<u64>,
+    read_bytes: u64,
+    _watchdog: Watchdog,
+}
+
+impl CgiProcessOutput {
+    /// Returns the status of the program, which is filled in while the
+    /// program runs and once it is reaped.
+    ///
+    pub fn status(&self) -> Arc<CgiProcessStatus> {
+        Arc::clone(&self.status)
+    }
+
+    /// Waits for the program to exit and records its exit status.
+    ///
+    fn reap(&mut self) {
+        match self.child.wait() {
+            Err(error) => debug!("Error waiting for CGI process: {:?}", error),
+            Ok(exit_status) => {
+                debug!("CGI process exited with {}", exit_status);
+                *self
+                    .status
+                    .exit_status
+                    .lock()
+                    .expect("Failed to acquire the CGI status mutex") = Some(exit_status);
+            }
+        }
+    }
+}
+
+impl Read for CgiProcessOutput {
+    /// Reads the program stdout. Once the output ends, the program is reaped
+    /// and an error is returned if it was stopped by the server or by the
+    /// system (see `CgiProcessStatus::failure_reason`), so that a truncated
+    /// output isn't mistaken for a complete one.
+    ///
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_bytes = match self.child.stdout.as_mut() {
+            None => return Ok(0),
+            Some(stdout) => stdout.read(buf)?,
+        };
+        self.read_bytes += read_bytes as u64;
+
+        if read_bytes == 0 && !buf.is_empty() {
+            drop(self.child.stdout.take());
+            self.reap();
+            if let Some(failure_reason) = self.status.failure_reason() {
+                return Err(io::Error::other(format!(
+                    "CGI output ended early: {failure_reason}"
+                )));
+            }
+        }
+
+        if let Some(max_output_size) = self.max_output_size {
+            if self.read_bytes > max_output_size {
+                warn!(
+                    "CGI process {} exceeded the {} bytes output limit, killing it",
+                    self.child.id(),
+                    max_output_size
+                );
+                self.status
+                    .output_limit_exceeded
+                    .store(true, Ordering::SeqCst);
+                kill_process_group(self.child.id());
+                drop(self.child.stdout.take());
+
+                return Err(io::Error::other("CGI output size limit exceeded"));
+            }
+        }
+
+        Ok(read_bytes)
+    }
+}
+
+impl Drop for CgiProcessOutput {
+    fn drop(&mut self) {
+        // Closing stdout first makes sure a script which is still writing
+        // (because the client went away) gets a broken pipe instead of
+        // blocking forever. The watchdog is still running at this point, so
+        // waiting is bounded by the timeout.
+        drop(self.child.stdout.take());
+        self.reap();
+    }
+}
+
+/// Runs the given CGI program with the given `input_data`,
+/// setting up the supplied environment variables. Returns the CGI program
+/// output stream if successful. Otherwise returns the error, which tells
+/// whether the program couldn't be started or wasn't allowed to run.
+///
+/// The input data is written from a separate thread, so that programs which
+/// produce output before reading all of their input don't block. The
+/// program runs in its own process group, which is killed if it runs for
+/// longer than `timeout` or writes more than `max_output_size` bytes to its
+/// stdout. The given resource limits are applied to the program before it
+/// starts. Its stderr output is handed to the given logger.
+///
+pub fn run_process(
+    program: CgiProgram,
+    input_data: &[u8],
+    env_variables: CGIMetavariableMap,
+    timeout: Duration,
+    resource_limits: CgiResourceLimits,
+    max_output_size: Option<u64>,
+    stderr_logger: CgiStderrLogger,
+) -> Result<CgiProcessOutput, CgiError> {
+    let mut script_process = program
+        .prepare_command(&env_variables, resource_limits)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    let status = Arc::new(CgiProcessStatus::default());
+    let watchdog = Watchdog::new(script_process.id(), timeout, Arc::clone(&status));
+
+    let stderr = script_process
+        .stderr
+        .take()
+        .ok_or_else(|| io::Error::other("Error getting stderr for child process"))?;
+    stderr_logger.spawn(stderr);
+
+    let mut stdin = script_process
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::other("Error getting stdin for child process"))?;
+    let input_data = input_data.to_vec();
+    thread::spawn(move || {
+        if let Err(error) = stdin.write_all(&input_data) {
+            debug!("Error writing CGI input: {:?}", error);
+        }
+    });
+
+    Ok(CgiProcessOutput {
+        child: script_process,
+        status,
+        max_output_size,
+        read_bytes: 0,
+        _watchdog: watchdog,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, os::unix::fs::PermissionsExt, time::Instant};
+
+    fn write_script(name: &str, contents: &str) -> PathBuf {
+        let script_path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::write(&script_path, contents).unwrap();
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)).unwrap();
+        script_path
+    }
+
+    fn run_script(
+        script_path: &PathBuf,
+        timeout: Duration,
+        resource_limits: CgiResourceLimits,
+        max_output_size: Option<u64>,
+    ) -> (io::Result<usize>, Arc<CgiProcessStatus>) {
+        let mut output = run_process(
+            CgiProgram {
+                script_path: script_path.clone(),
+                interpreter: None,
+            },
+            b"",
+            CGIMetavariableMap::new(),
+            timeout,
+            resource_limits,
+            max_output_size,
+            CgiStderrLogger {
+                script: script_path.to_string_lossy().to_string(),
+                request_id: String::from("test"),
+                client_address: String::from("test"),
+                level: log::Level::Warn,
+                error_log: None,
+            },
+        )
+        .unwrap();
+        let status = output.status();
+
+        let mut contents = Vec::new();
+        let read_result = output.read_to_end(&mut contents);
+        drop(output);
+        fs::remove_file(script_path).unwrap();
+
+        (read_result, status)
+    }
+
+    #[test]
+    fn hanging_process_group_is_killed_after_timeout() {
+        let script_path = write_script("hanging.sh", "#!/bin/sh\nsleep 30 &\nsleep 30\n");
+
+        let started = Instant::now();
+        let (read_result, status) = run_script(
+            &script_path,
+            Duration::from_millis(200),
+            CgiResourceLimits::default(),
+            None,
+        );
+
+        assert!(read_result.is_err());
+        assert!(status.timed_out());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn output_larger_than_limit_is_cut_off() {
+        let script_path = write_script("chatty.sh", "#!/bin/sh\nwhile true; do echo spam; done\n");
+
+        let (read_result, status) = run_script(
+            &script_path,
+            Duration::from_secs(10),
+            CgiResourceLimits::default(),
+            Some(1024),
+        );
+
+        assert!(read_result.is_err());
+        assert!(status.output_limit_exceeded());
+        assert!(!status.timed_out());
+    }
+
+    #[test]
+    fn interpreter_command_gets_script_path() {
+        let program = CgiProgram {
+            script_path: PathBuf::from("/srv/cgi-bin/form.php"),
+            interpreter: Some(String::from("php-cgi -q")),
+        };
+        let command = program.command();
+
+        assert_eq!(command.get_program(), "php-cgi");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["-q", "/srv/cgi-bin/form.php"]
+        );
+    }
+
+    #[test]
+    fn cpu_limit_is_applied() {
+        let script_path = write_script("busy.sh", "#!/bin/sh\nwhile true; do :; done\n");
+
+        let (_, status) = run_script(
+            &script_path,
+            Duration::from_secs(10),
+            CgiResourceLimits {
+                cpu_seconds: Some(1),
+                ..Default::default()
+            },
+            None,
+        );
+
+        assert_eq!(
+            status.failure_reason().as_deref(),
+            Some("CPU time limit exceeded")
+        );
+    }
+}
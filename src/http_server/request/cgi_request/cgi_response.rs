@@ -1,16 +1,18 @@
-use http::{Request, Response, StatusCode};
+use http::{
+    header, uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue, Response, StatusCode, Uri,
+};
 
 use std::{
     collections::HashMap,
-    net::TcpStream,
-    str::{FromStr, Lines},
+    io::{self, Cursor, Read},
+    str::FromStr,
 };
 
 use log::debug;
 
 use crate::http_server::{
-    request::{request::RequestHandler, static_request::static_handler::StaticRequestHandler},
-    response::generate_error_response,
+    request::{cgi_request::cgi_error::CgiError, request::find_metadata_end},
+    response::{LocalRedirect, ResponseBody, HOP_BY_HOP_HEADERS},
 };
 
 #[derive(strum_macros::EnumString, Eq, Hash, PartialEq, Debug)]
@@ -23,31 +25,54 @@ pub enum CGIResponseHeader {
 
 pub type CGIResponseHeaderMap = HashMap<CGIResponseHeader, String>;
 
+const MAX_CGI_HEADERS_SIZE: usize = 64 * 1024; // 64KB
+
+/// Response of a CGI script. The body is either the whole output following
+/// the header block (`Vec<u8>`) or a `ResponseBody` streaming it as the
+/// script produces it. Headers other than the CGI ones are kept in
+/// `extra_headers`, to be passed along to the client.
 #[derive(Debug, PartialEq)]
-pub struct CGIScriptResponse {
+pub struct CGIScriptResponse<B = Vec<u8>> {
     headers: CGIResponseHeaderMap,
-    body: String,
+    extra_headers: HeaderMap,
+    body: B,
 }
 
-impl CGIScriptResponse {
-    fn new(headers: CGIResponseHeaderMap, body: String) -> CGIScriptResponse {
-        CGIScriptResponse { headers, body }
+impl<B> CGIScriptResponse<B> {
+    fn new(
+        headers: CGIResponseHeaderMap,
+        extra_headers: HeaderMap,
+        body: B,
+    ) -> CGIScriptResponse<B> {
+        CGIScriptResponse {
+            headers,
+            extra_headers,
+            body,
+        }
+    }
+
+    pub fn headers(&self) -> &CGIResponseHeaderMap {
+        &self.headers
+    }
+
+    pub fn extra_headers(&self) -> &HeaderMap {
+        &self.extra_headers
     }
 }
 
-/// Extracts the CGI headers returned from the CGI script.
+/// Extracts the CGI headers returned from the CGI script. The given header
+/// block is expected to include the blank line which ends it. Headers which
+/// aren't CGI headers are returned separately, with hop-by-hop headers and
+/// headers which aren't valid HTTP headers left out. A `Content-Length`
+/// header must be a valid length, since it is used to delimit the body sent
+/// to the client (the body is then cut off at that length, and a body
+/// ending early makes the server close the connection).
 ///
-fn parse_cgi_headers(cgi_output: &mut Lines) -> Result<CGIResponseHeaderMap, ()> {
+fn parse_cgi_headers(header_block: &str) -> Result<(CGIResponseHeaderMap, HeaderMap), CgiError> {
     let mut headers = CGIResponseHeaderMap::new();
+    let mut extra_headers = HeaderMap::new();
 
-    loop {
-        let next_line = if let Some(line_result) = cgi_output.next() {
-            line_result
-        } else {
-            debug!("Malformed CGI response");
-            return Err(());
-        };
-
+    for next_line in header_block.lines() {
         if next_line.is_empty() {
             break;
         }
@@ -55,129 +80,305 @@ fn parse_cgi_headers(cgi_output: &mut Lines) -> Result<CGIResponseHeaderMap, ()>
         let split_line = next_line.split_once(":");
         match split_line {
             None => {
-                debug!("Invalid CGI header");
-                return Err(());
+                return Err(CgiError::InvalidHeaderSyntax(format!(
+                    "line without a colon: {next_line:?}"
+                )));
             }
             Some((before, after)) => {
                 let header_value = CGIResponseHeader::from_str(before);
 
                 if let Ok(header_key) = header_value {
                     headers.insert(header_key, after.trim().to_string());
-                } else if let Err(_) = header_value {
-                    debug!("Couldn't parse header: {:?}", before);
+                    continue;
+                }
+
+                let extra_header = (
+                    HeaderName::from_str(before.trim()),
+                    HeaderValue::from_str(after.trim()),
+                );
+                match extra_header {
+                    (Ok(header_name), Ok(header_value)) => {
+                        if header_name == header::CONTENT_LENGTH
+                            && (extra_headers.contains_key(header::CONTENT_LENGTH)
+                                || after.trim().parse::<u64>().is_err())
+                        {
+                            return Err(CgiError::InvalidHeaderSyntax(format!(
+                                "invalid or repeated content length: {:?}",
+                                after.trim()
+                            )));
+                        }
+
+                        if HOP_BY_HOP_HEADERS.contains(&header_name) {
+                            debug!("Ignoring hop-by-hop header: {:?}", header_name);
+                        } else {
+                            extra_headers.append(header_name, header_value);
+                        }
+                    }
+                    _ => debug!("Couldn't parse header: {:?}", before),
                 }
             }
         }
     }
 
-    Ok(headers)
+    Ok((headers, extra_headers))
 }
 
-/// Extracts the CGI response from the CGI script output
+/// Extracts the CGI response from the CGI script output. The header block
+/// must be valid UTF-8, while the body is passed along untouched.
 ///
-pub fn parse_cgi_response(cgi_output: String) -> Result<CGIScriptResponse, ()> {
-    let mut output_lines = cgi_output.lines();
-    let response_headers = parse_cgi_headers(&mut output_lines);
-    let response_headers = match response_headers {
-        Err(_) => return Err(()),
-        Ok(headers) => headers,
+pub fn parse_cgi_response(mut cgi_output: Vec<u8>) -> Result<CGIScriptResponse, CgiError> {
+    let headers_end = match find_metadata_end(&cgi_output) {
+        None => {
+            return Err(CgiError::InvalidHeaderSyntax(String::from(
+                "header block isn't terminated by a blank line",
+            )));
+        }
+        Some(headers_end) => headers_end,
     };
 
-    let response_body = output_lines.collect::<String>();
-    Ok(CGIScriptResponse::new(response_headers, response_body))
+    let response_body = cgi_output.split_off(headers_end);
+    let header_block = match String::from_utf8(cgi_output) {
+        Err(_) => {
+            return Err(CgiError::InvalidHeaderSyntax(String::from(
+                "header block isn't valid UTF-8",
+            )));
+        }
+        Ok(header_block) => header_block,
+    };
+
+    let (response_headers, extra_headers) = parse_cgi_headers(&header_block)?;
+    Ok(CGIScriptResponse::new(
+        response_headers,
+        extra_headers,
+        response_body,
+    ))
 }
 
-/// Converts a CGI Local Redirect response into the corresponding HTTP response
+/// Reads the CGI response from a stream carrying the CGI script output. Only
+/// the header block is read here: the returned body streams the rest of the
+/// output as it is produced, so that it can be sent to the client right
+/// away.
 ///
-fn local_redirect(
-    stream: &TcpStream,
-    static_handler: &StaticRequestHandler,
-    location: &str,
-) -> Response<String> {
-    let static_request = Request::builder()
-        .method("GET")
-        .uri(location)
-        .body(String::from(""));
-
-    match static_request {
-        Err(_) => generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
-        Ok(static_request) => {
-            let response = static_handler.handle_request(stream, &static_request);
-
-            match response {
-                None => generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
-                Some(response) => response,
+pub fn read_cgi_response<R: Read + Send + 'static>(
+    mut cgi_output: R,
+) -> Result<CGIScriptResponse<ResponseBody>, CgiError> {
+    let mut header_data = Vec::new();
+    let mut buffer = [0; 8 * 1024];
+
+    let headers_end = loop {
+        if let Some(headers_end) = find_metadata_end(&header_data) {
+            break headers_end;
+        }
+
+        if header_data.len() > MAX_CGI_HEADERS_SIZE {
+            return Err(CgiError::InvalidHeaderSyntax(String::from(
+                "header block is too large",
+            )));
+        }
+
+        match cgi_output.read(&mut buffer) {
+            Ok(0) => {
+                return Err(CgiError::InvalidHeaderSyntax(String::from(
+                    "output ended before the end of the header block",
+                )));
+            }
+            Err(error) => {
+                return Err(CgiError::InvalidHeaderSyntax(format!(
+                    "error reading the header block: {error}"
+                )));
             }
+            Ok(read_bytes) => header_data.extend_from_slice(&buffer[..read_bytes]),
         }
+    };
+
+    let body_start = header_data.split_off(headers_end);
+    let CGIScriptResponse {
+        headers,
+        extra_headers,
+        ..
+    } = parse_cgi_response(header_data)?;
+    let body = ResponseBody::from_reader(Cursor::new(body_start).chain(cgi_output), None);
+
+    Ok(CGIScriptResponse::new(headers, extra_headers, body))
+}
+
+/// Converts a CGI Local Redirect response into a response carrying a
+/// `LocalRedirect` extension, so that the connection handler processes the
+/// request again with the given location (a path, optionally followed by a
+/// query string).
+///
+fn local_redirect(location: &str) -> Result<Response<ResponseBody>, CgiError> {
+    if PathAndQuery::from_str(location).is_err() {
+        return Err(invalid_location(location));
     }
+
+    let mut response = Response::new(ResponseBody::empty());
+    response.extensions_mut().insert(LocalRedirect {
+        location: location.to_string(),
+    });
+
+    Ok(response)
 }
 
 /// Converts a CGI Client Redirect response into the corresponding HTTP
 /// response
 ///
-fn client_redirect(location: &str) -> Response<String> {
-    let response = Response::builder()
+fn client_redirect(location: &str) -> Result<Response<ResponseBody>, CgiError> {
+    Response::builder()
         .status(StatusCode::FOUND)
         .header("location", location)
-        .body(String::from(""));
-
-    match response {
-        Err(_) => generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
-        Ok(response) => response,
-    }
+        .body(ResponseBody::empty())
+        .map_err(|_| invalid_location(location))
 }
 
-/// Converts a CGI Document response into the corresponding HTTP response
+/// Converts a CGI Client Redirect response with document into the
+/// corresponding HTTP response. The CGI script must supply a redirection
+/// (3xx) status along with the location, as required by section 6.2.4 of the
+/// CGI RFC. A content type is only required when there is a document: a
+/// redirect with an empty body is sent as it is, with the given status.
 ///
-fn document_response(headers: CGIResponseHeaderMap, body: String) -> Response<String> {
+fn client_redirect_with_document(
+    location: &str,
+    headers: CGIResponseHeaderMap,
+    body: ResponseBody,
+) -> Result<Response<ResponseBody>, CgiError> {
     let status = match headers.get(&CGIResponseHeader::Status) {
-        None => String::from(StatusCode::OK.as_str()),
-        Some(status) => status.clone(),
+        None => return Err(CgiError::MissingHeader("Status")),
+        Some(status) => parse_status(status)?,
     };
+    if !status.is_redirection() {
+        return Err(CgiError::BadStatus(format!(
+            "{status} isn't a redirection status"
+        )));
+    }
+
+    let location = HeaderValue::from_str(location).map_err(|_| invalid_location(location))?;
+    let mut response = document_response(headers, body)?;
+    response.headers_mut().insert(header::LOCATION, location);
+
+    Ok(response)
+}
+
+/// Helper function which parses the value of a CGI `Status` header, made of a
+/// status code optionally followed by a reason phrase.
+///
+fn parse_status(status: &str) -> Result<StatusCode, CgiError> {
+    status
+        .split_whitespace()
+        .next()
+        .and_then(|status_code| StatusCode::from_str(status_code).ok())
+        .ok_or_else(|| CgiError::BadStatus(status.to_string()))
+}
+
+/// Helper function which checks whether a response body is empty. A streamed
+/// body of unknown length is checked by reading its first byte, which is put
+/// back in front of the stream.
+///
+fn is_empty_body(body: &mut ResponseBody) -> bool {
+    match body {
+        ResponseBody::Bytes(bytes) => bytes.is_empty(),
+        ResponseBody::Stream {
+            length: Some(length),
+            ..
+        } => *length == 0,
+        ResponseBody::Stream { reader, .. } => {
+            let mut first_byte = [0; 1];
+            match reader.read(&mut first_byte) {
+                Ok(0) => true,
+                Err(_) => false,
+                Ok(_) => {
+                    let rest = std::mem::replace(reader, Box::new(io::empty()));
+                    *reader = Box::new(Cursor::new(first_byte).chain(rest));
+                    false
+                }
+            }
+        }
+        ResponseBody::Raw(_) => false,
+    }
+}
+
+/// Helper function which builds the error returned for a `Location` header
+/// which can't be used.
+///
+fn invalid_location(location: &str) -> CgiError {
+    CgiError::InvalidHeaderSyntax(format!("invalid location: {location:?}"))
+}
+
+/// Helper function which checks whether a location is an absolute URI, as
+/// required for client redirects.
+///
+fn is_absolute_uri(location: &str) -> bool {
+    Uri::from_str(location).is_ok_and(|uri| uri.scheme().is_some())
+}
 
-    let status = match StatusCode::from_str(status.as_str()) {
-        Err(_) => return generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
-        Ok(value) => value,
+/// Converts a CGI Document response into the corresponding HTTP response.
+/// As required by section 6.3.1 of the CGI RFC, a content type must be
+/// supplied when there is a body, while responses without one (such as a
+/// `204 No Content` response) may leave it out.
+///
+fn document_response(
+    headers: CGIResponseHeaderMap,
+    mut body: ResponseBody,
+) -> Result<Response<ResponseBody>, CgiError> {
+    let status = match headers.get(&CGIResponseHeader::Status) {
+        None => StatusCode::OK,
+        Some(status) => parse_status(status)?,
     };
 
     let content_type = match headers.get(&CGIResponseHeader::ContentType) {
-        None => return generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
+        None if is_empty_body(&mut body) => {
+            let mut response = Response::new(body);
+            *response.status_mut() = status;
+            return Ok(response);
+        }
+        None => return Err(CgiError::MissingHeader("Content-Type")),
         Some(value) => value,
     };
 
-    let response = Response::builder()
+    Response::builder()
         .status(status)
         .header("content-type", content_type)
-        .body(body);
-
-    match response {
-        Err(_) => generate_error_response(StatusCode::INTERNAL_SERVER_ERROR),
-        Ok(response) => response,
-    }
+        .body(body)
+        .map_err(|_| {
+            CgiError::InvalidHeaderSyntax(format!("invalid content type: {content_type:?}"))
+        })
 }
 
 /// Converts a CGI response into the corresponding HTTP response. The type of
 /// CGI response is inferred by the CGI headers present in the CGI script
-/// output.
+/// output: a location starting with a slash is a local redirect, while an
+/// absolute URI is a client redirect, which carries a document if the script
+/// also supplied a status or a content type. Any other location is rejected.
+/// Any extra headers sent by the CGI script are added to client redirect and
+/// document responses. Returns an error if the headers don't make up a valid
+/// CGI response.
 ///
-pub fn convert_cgi_response_to_http(
-    stream: &TcpStream,
-    static_handler: &StaticRequestHandler,
-    cgi_response: CGIScriptResponse,
-) -> Response<String> {
+pub fn convert_cgi_response_to_http<B: Into<ResponseBody>>(
+    cgi_response: CGIScriptResponse<B>,
+) -> Result<Response<ResponseBody>, CgiError> {
     let response_headers = cgi_response.headers;
-    let response_body = cgi_response.body;
-
-    if response_headers.contains_key(&CGIResponseHeader::Location) {
-        let location = &response_headers[&CGIResponseHeader::Location];
-        if location.starts_with("/") {
-            local_redirect(stream, static_handler, location)
-        } else {
-            client_redirect(location)
+    let response_body = cgi_response.body.into();
+
+    let mut response = match response_headers.get(&CGIResponseHeader::Location).cloned() {
+        None => document_response(response_headers, response_body)?,
+        Some(location) if location.starts_with("/") => {
+            return local_redirect(&location);
         }
-    } else {
-        document_response(response_headers, response_body)
-    }
+        Some(location) if !is_absolute_uri(&location) => {
+            return Err(invalid_location(&location));
+        }
+        Some(location)
+            if response_headers.contains_key(&CGIResponseHeader::Status)
+                || response_headers.contains_key(&CGIResponseHeader::ContentType) =>
+        {
+            client_redirect_with_document(&location, response_headers, response_body)?
+        }
+        Some(location) => client_redirect(&location)?,
+    };
+
+    response.headers_mut().extend(cgi_response.extra_headers);
+
+    Ok(response)
 }
 
 #[cfg(test)]
@@ -186,12 +387,11 @@ mod tests {
 
     #[test]
     fn cgi_response_is_properly_parsed() {
-        let mock_cgi_output = String::from(
-            "\
+        let mock_cgi_output = b"\
             Content-Type: text/html\n\n\
             Hello!\
-        ",
-        );
+        "
+        .to_vec();
         let result = parse_cgi_response(mock_cgi_output);
 
         let cgi_response = result.unwrap();
@@ -201,8 +401,162 @@ mod tests {
             String::from("text/html"),
         )]);
 
-        let expected = CGIScriptResponse::new(expected_headers, String::from("Hello!"));
+        let expected =
+            CGIScriptResponse::new(expected_headers, HeaderMap::new(), b"Hello!".to_vec());
 
         assert_eq!(cgi_response, expected);
     }
+
+    #[test]
+    fn extra_cgi_headers_are_kept() {
+        let mock_cgi_output = b"\
+            Content-Type: text/html\n\
+            Set-Cookie: a=1\n\
+            Set-Cookie: b=2\n\
+            Cache-Control: no-store\n\
+            Connection: close\n\
+            Transfer-Encoding: chunked\n\n\
+            Hello!\
+        "
+        .to_vec();
+        let cgi_response = parse_cgi_response(mock_cgi_output).unwrap();
+
+        let extra_headers = cgi_response.extra_headers();
+        assert_eq!(extra_headers.len(), 3);
+        assert_eq!(
+            extra_headers
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+        assert_eq!(extra_headers[header::CACHE_CONTROL], "no-store");
+    }
+
+    #[test]
+    fn invalid_content_length_is_rejected() {
+        for cgi_output in [
+            &b"Content-Type: text/plain\nContent-Length: -5\n\nHello"[..],
+            &b"Content-Type: text/plain\nContent-Length: 5\nContent-Length: 6\n\nHello"[..],
+        ] {
+            assert!(matches!(
+                parse_cgi_response(cgi_output.to_vec()),
+                Err(CgiError::InvalidHeaderSyntax(_))
+            ));
+        }
+
+        let cgi_response =
+            parse_cgi_response(b"Content-Type: text/plain\nContent-Length: 5\n\nHello".to_vec())
+                .unwrap();
+        assert_eq!(cgi_response.extra_headers()[header::CONTENT_LENGTH], "5");
+    }
+
+    #[test]
+    fn client_redirect_with_document_is_validated() {
+        let convert = |cgi_output: &[u8]| {
+            let cgi_response = parse_cgi_response(cgi_output.to_vec()).unwrap();
+            let headers = cgi_response.headers;
+            let location = headers[&CGIResponseHeader::Location].clone();
+            client_redirect_with_document(&location, headers, cgi_response.body.into())
+        };
+
+        let response = convert(
+            b"Location: https://example.com/\nStatus: 301 Moved Permanently\n\
+            Content-Type: text/html\n\nMoved",
+        );
+        let response = response.unwrap();
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "https://example.com/");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(response.body().len(), Some(5));
+
+        let response =
+            convert(b"Location: https://example.com/\nStatus: 200\nContent-Type: text/html\n\n");
+        assert!(matches!(response, Err(CgiError::BadStatus(_))));
+
+        let response = convert(b"Location: https://example.com/\nStatus: 302\n\nMoved");
+        assert!(matches!(
+            response,
+            Err(CgiError::MissingHeader("Content-Type"))
+        ));
+    }
+
+    #[test]
+    fn client_redirect_without_document_keeps_status() {
+        let cgi_output = b"Location: https://example.com/\nStatus: 303 See Other\n\n";
+
+        let cgi_response = parse_cgi_response(cgi_output.to_vec()).unwrap();
+        let response = convert_cgi_response_to_http(cgi_response).unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()[header::LOCATION], "https://example.com/");
+        assert!(response.headers().get(header::CONTENT_TYPE).is_none());
+        assert_eq!(response.body().len(), Some(0));
+
+        let cgi_response = read_cgi_response(Cursor::new(cgi_output.to_vec())).unwrap();
+        let response = convert_cgi_response_to_http(cgi_response).unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let cgi_response = read_cgi_response(Cursor::new(
+            b"Location: https://example.com/\nStatus: 302\n\nMoved",
+        ))
+        .unwrap();
+        assert!(matches!(
+            convert_cgi_response_to_http(cgi_response),
+            Err(CgiError::MissingHeader("Content-Type"))
+        ));
+    }
+
+    #[test]
+    fn local_redirect_keeps_query_string() {
+        let cgi_response =
+            parse_cgi_response(b"Location: /cgi-bin/test.sh?a=1&b=2\n\n".to_vec()).unwrap();
+        let response = convert_cgi_response_to_http(cgi_response).unwrap();
+
+        assert_eq!(
+            response.extensions().get::<LocalRedirect>(),
+            Some(&LocalRedirect {
+                location: String::from("/cgi-bin/test.sh?a=1&b=2")
+            })
+        );
+    }
+
+    #[test]
+    fn client_redirect_location_must_be_absolute() {
+        assert!(is_absolute_uri("https://example.com/page?a=1"));
+        assert!(!is_absolute_uri("example.com/page"));
+        assert!(!is_absolute_uri("page.html"));
+    }
+
+    #[test]
+    fn cgi_response_body_line_breaks_are_preserved() {
+        let mock_cgi_output =
+            b"Content-Type: text/plain\r\n\r\nfirst\r\nsecond\n\nthird\n".to_vec();
+        let result = parse_cgi_response(mock_cgi_output);
+
+        let cgi_response = result.unwrap();
+
+        assert_eq!(cgi_response.body, b"first\r\nsecond\n\nthird\n");
+    }
+
+    #[test]
+    fn cgi_response_body_is_streamed_after_headers() {
+        let mock_cgi_output =
+            Cursor::new(b"Status: 200\nContent-Type: text/plain\n\nfirst\nsecond\n");
+        let cgi_response = read_cgi_response(mock_cgi_output).unwrap();
+
+        assert_eq!(
+            cgi_response.headers()[&CGIResponseHeader::ContentType],
+            "text/plain"
+        );
+
+        let mut body = Vec::new();
+        match cgi_response.body {
+            ResponseBody::Stream {
+                mut reader,
+                length: None,
+            } => reader.read_to_end(&mut body).unwrap(),
+            _ => panic!("CGI response body should be streamed"),
+        };
+        assert_eq!(body, b"first\nsecond\n");
+    }
 }
@@ -0,0 +1,3 @@
+pub mod fastcgi_handler;
+pub mod fastcgi_process_manager;
+pub mod fastcgi_protocol;
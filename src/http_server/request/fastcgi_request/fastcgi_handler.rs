@@ -0,0 +1,686 @@
+use std::{
+    io::{self, BufWriter, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use http::{HeaderName, Request, Response};
+
+use log::{debug, warn, Level};
+
+use crate::http_server::{
+    request::{
+        backend::{is_timeout, split_path, BackendAddress, BackendStream},
+        cgi_request::{
+            cgi_error::CgiError,
+            cgi_error_log::{CgiErrorLog, CgiStderrLogger},
+            cgi_handler::{default_excluded_headers, generate_environment_variables},
+            cgi_metavariables::CGIMetavariable,
+            cgi_response::{convert_cgi_response_to_http, read_cgi_response},
+        },
+        fastcgi_request::{
+            fastcgi_process_manager::{FastCgiProcessLease, FastCgiProcessManager},
+            fastcgi_protocol::{
+                encode_params, read_record, write_begin_request, write_stream, EndRequest,
+                RecordType, FCGI_KEEP_CONN, FCGI_REQUEST_COMPLETE, FCGI_RESPONDER,
+            },
+        },
+        request::{RequestHandler, RequestId},
+    },
+    response::ResponseBody,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// Connections to the application carry a single request at a time, so every
+/// request uses the same id.
+const REQUEST_ID: u16 = 1;
+
+/// Tunable options for the communication with a FastCGI application.
+pub struct FastCgiSettings {
+    /// Maximum time spent connecting to the application, and waiting for it
+    /// to accept or send data.
+    pub timeout: Duration,
+    /// Maximum number of idle connections kept open to be reused by later
+    /// requests.
+    pub max_idle_connections: usize,
+    /// Request headers which are not passed to the application as `HTTP_*`
+    /// parameters (see `CgiSettings::excluded_headers`).
+    pub excluded_headers: Vec<HeaderName>,
+    /// Value of the `SCRIPT_FILENAME` parameter, which some applications
+    /// (such as PHP-FPM) use to find the script to run.
+    pub script_filename: Option<String>,
+    /// Level at which the stderr output of the application is logged.
+    pub stderr_log_level: Level,
+    /// File to which the stderr output of the application is also appended,
+    /// if any.
+    pub error_log: Option<Arc<CgiErrorLog>>,
+    /// Whether error responses describe what went wrong with the
+    /// application. Meant for development only.
+    pub detailed_errors: bool,
+}
+
+impl Default for FastCgiSettings {
+    fn default() -> FastCgiSettings {
+        FastCgiSettings {
+            timeout: DEFAULT_TIMEOUT,
+            max_idle_connections: DEFAULT_MAX_IDLE_CONNECTIONS,
+            excluded_headers: default_excluded_headers(),
+            script_filename: None,
+            stderr_log_level: Level::Warn,
+            error_log: None,
+            detailed_errors: false,
+        }
+    }
+}
+
+/// Pool of idle connections to a FastCGI application.
+struct ConnectionPool {
+    address: BackendAddress,
+    timeout: Duration,
+    max_idle_connections: usize,
+    idle_connections: Mutex<Vec<BackendStream>>,
+}
+
+impl ConnectionPool {
+    fn new(
+        address: BackendAddress,
+        timeout: Duration,
+        max_idle_connections: usize,
+    ) -> ConnectionPool {
+        ConnectionPool {
+            address,
+            timeout,
+            max_idle_connections,
+            idle_connections: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Opens a new connection to the application.
+    ///
+    fn connect(&self) -> io::Result<BackendStream> {
+        debug!(
+            "Connecting to the FastCGI application at {:?}",
+            self.address
+        );
+        BackendStream::connect(&self.address, self.timeout)
+    }
+
+    /// Returns an idle connection if there is one, or a new connection
+    /// otherwise. The returned flag tells whether the connection was reused.
+    ///
+    fn get(&self) -> io::Result<(BackendStream, bool)> {
+        let idle_connection = self.lock().pop();
+        match idle_connection {
+            Some(connection) => Ok((connection, true)),
+            None => Ok((self.connect()?, false)),
+        }
+    }
+
+    /// Keeps a connection whose last request is over, to be reused later.
+    ///
+    fn put(&self, connection: BackendStream) {
+        let mut idle_connections = self.lock();
+        if idle_connections.len() < self.max_idle_connections {
+            idle_connections.push(connection);
+        }
+    }
+
+    /// Closes all idle connections.
+    ///
+    fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<BackendStream>> {
+        self.idle_connections
+            .lock()
+            .expect("Failed to acquire the FastCGI connection pool mutex")
+    }
+}
+
+/// Output of a FastCGI request. Reading from it reads the `STDOUT` stream
+/// sent by the application, while the `STDERR` stream is logged. Once the
+/// request is over, the connection goes back to the pool.
+struct FastCgiResponseReader {
+    connection: Option<BackendStream>,
+    pool: Arc<ConnectionPool>,
+    stderr_logger: CgiStderrLogger,
+    timed_out: Arc<AtomicBool>,
+    buffer: Vec<u8>,
+    position: usize,
+    /// Whether any record of the request was received from the application.
+    received_records: bool,
+    /// Application process handling the request, when the application is
+    /// run by the server. It is released once the request is over.
+    lease: Option<FastCgiProcessLease>,
+}
+
+impl FastCgiResponseReader {
+    /// Reads the next record sent by the application, buffering its
+    /// `STDOUT` data.
+    ///
+    fn read_next_record(&mut self) -> io::Result<()> {
+        let connection = match self.connection.as_mut() {
+            None => return Ok(()),
+            Some(connection) => connection,
+        };
+
+        let record = match read_record(connection) {
+            Err(error) => {
+                if is_timeout(&error) {
+                    self.timed_out.store(true, Ordering::SeqCst);
+                }
+                self.connection = None;
+                return Err(error);
+            }
+            Ok(record) => record,
+        };
+        self.received_records = true;
+
+        if record.request_id != REQUEST_ID {
+            debug!("Ignoring FastCGI record {:?}", record.record_type);
+            return Ok(());
+        }
+
+        match record.record_type {
+            RecordType::Stdout => {
+                self.buffer = record.content;
+                self.position = 0;
+            }
+            RecordType::Stderr => {
+                for line in String::from_utf8_lossy(&record.content).lines() {
+                    self.stderr_logger.log_line(line);
+                }
+            }
+            RecordType::EndRequest => {
+                let end_request = EndRequest::parse(&record.content)?;
+                debug!("FastCGI request ended: {:?}", end_request);
+                if end_request.protocol_status != FCGI_REQUEST_COMPLETE {
+                    warn!(
+                        "FastCGI application rejected the request (protocol status {})",
+                        end_request.protocol_status
+                    );
+                }
+
+                if let Some(connection) = self.connection.take() {
+                    self.pool.put(connection);
+                }
+                drop(self.lease.take());
+            }
+            record_type => debug!("Ignoring FastCGI record {:?}", record_type),
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the application starts answering the request.
+    ///
+    fn wait_for_output(&mut self) -> io::Result<()> {
+        while self.position >= self.buffer.len() && self.connection.is_some() {
+            self.read_next_record()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for FastCgiResponseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.wait_for_output()?;
+
+        let available = &self.buffer[self.position..];
+        let read_bytes = available.len().min(buf.len());
+        buf[..read_bytes].copy_from_slice(&available[..read_bytes]);
+        self.position += read_bytes;
+
+        Ok(read_bytes)
+    }
+}
+
+/// FastCGI application the requests are sent to.
+enum FastCgiBackend {
+    /// Application started on its own, listening at a known address.
+    Address(Arc<ConnectionPool>),
+    /// Application processes started and supervised by the server.
+    Managed(FastCgiProcessManager),
+}
+
+/// Request handler forwarding the requests under a given path to a FastCGI
+/// application, which keeps running between requests.
+pub struct FastCgiRequestHandler {
+    path: String,
+    static_folder: String,
+    backend: FastCgiBackend,
+    settings: FastCgiSettings,
+}
+
+impl FastCgiRequestHandler {
+    pub fn new(
+        path: String,
+        address: BackendAddress,
+        static_folder: String,
+        settings: FastCgiSettings,
+    ) -> FastCgiRequestHandler {
+        let pool = Arc::new(ConnectionPool::new(
+            address,
+            settings.timeout,
+            settings.max_idle_connections,
+        ));
+
+        FastCgiRequestHandler {
+            path,
+            static_folder,
+            backend: FastCgiBackend::Address(pool),
+            settings,
+        }
+    }
+
+    /// Creates a handler sending its requests to the application processes
+    /// run by the given manager. Unless set, the `SCRIPT_FILENAME` parameter
+    /// is the path to the script the processes run.
+    ///
+    pub fn with_process_manager(
+        path: String,
+        manager: FastCgiProcessManager,
+        static_folder: String,
+        mut settings: FastCgiSettings,
+    ) -> FastCgiRequestHandler {
+        settings
+            .script_filename
+            .get_or_insert_with(|| manager.script_path().to_string_lossy().to_string());
+
+        FastCgiRequestHandler {
+            path,
+            static_folder,
+            backend: FastCgiBackend::Managed(manager),
+            settings,
+        }
+    }
+
+    /// Forwards the request to the application and converts its output into
+    /// an HTTP response.
+    ///
+    fn forward_request(
+        &self,
+        stream: &TcpStream,
+        request: &Request<Vec<u8>>,
+        script_name: &str,
+        path_info: &str,
+    ) -> Result<Response<ResponseBody>, CgiError> {
+        let mut params = generate_environment_variables(
+            stream,
+            request,
+            script_name,
+            path_info,
+            &self.static_folder,
+            &self.settings.excluded_headers,
+        );
+        if let Some(script_filename) = &self.settings.script_filename {
+            params.insert(
+                CGIMetavariable::ProtocolSpecific(String::from("SCRIPT_FILENAME")),
+                script_filename.clone(),
+            );
+        }
+        let params = encode_params(params.iter().map(|(name, value)| (name.name(), &value[..])));
+
+        let stderr_logger = CgiStderrLogger {
+            script: format!("{}{}", script_name, path_info),
+            request_id: request
+                .extensions()
+                .get::<RequestId>()
+                .map_or(String::from("-"), |request_id| request_id.to_string()),
+            client_address: stream
+                .peer_addr()
+                .map_or(String::from("-"), |addr| addr.to_string()),
+            level: self.settings.stderr_log_level,
+            error_log: self.settings.error_log.clone(),
+        };
+
+        let (pool, lease) = match &self.backend {
+            FastCgiBackend::Address(pool) => (Arc::clone(pool), None),
+            FastCgiBackend::Managed(manager) => {
+                let lease = manager.acquire(self.settings.timeout)?;
+                // Processes serve a single connection at a time, so their
+                // connections aren't kept once the request is over.
+                let pool = ConnectionPool::new(lease.address().clone(), self.settings.timeout, 0);
+                (Arc::new(pool), Some(lease))
+            }
+        };
+
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let reader = send_request(
+            &pool,
+            &params,
+            request.body(),
+            request.method().is_idempotent(),
+            stderr_logger,
+            lease,
+            Arc::clone(&timed_out),
+        )?;
+
+        let cgi_response = match read_cgi_response(reader) {
+            Err(_) if timed_out.load(Ordering::SeqCst) => return Err(CgiError::Timeout),
+            Err(error) => return Err(error),
+            Ok(cgi_response) => cgi_response,
+        };
+
+        debug!("FastCGI headers: {:?}", cgi_response.headers());
+        convert_cgi_response_to_http(cgi_response)
+    }
+}
+
+/// Writes a whole FastCGI request: the `BEGIN_REQUEST` record, followed by
+/// the `PARAMS` and `STDIN` streams.
+///
+fn write_request<W: Write>(connection: &mut W, params: &[u8], body: &[u8]) -> io::Result<()> {
+    let mut writer = BufWriter::new(connection);
+    write_begin_request(&mut writer, REQUEST_ID, FCGI_RESPONDER, FCGI_KEEP_CONN)?;
+    write_stream(&mut writer, RecordType::Params, REQUEST_ID, params)?;
+    write_stream(&mut writer, RecordType::Stdin, REQUEST_ID, body)?;
+    writer.flush()
+}
+
+/// Sends the request to the application. Requests which fail on a reused
+/// connection (which the application may have closed in the meantime)
+/// are sent again over a new connection, as long as the application
+/// can't have acted on them: either the request couldn't be written, or
+/// the connection was closed without any answer and the request is
+/// `idempotent`.
+///
+fn send_request(
+    pool: &Arc<ConnectionPool>,
+    params: &[u8],
+    body: &[u8],
+    idempotent: bool,
+    stderr_logger: CgiStderrLogger,
+    mut lease: Option<FastCgiProcessLease>,
+    timed_out: Arc<AtomicBool>,
+) -> Result<FastCgiResponseReader, CgiError> {
+    let (mut connection, mut reused) = pool.get().map_err(CgiError::ConnectionFailed)?;
+    let mut stderr_logger = Some(stderr_logger);
+
+    loop {
+        let sent = write_request(&mut connection, params, body);
+        let mut reader = FastCgiResponseReader {
+            connection: Some(connection),
+            pool: Arc::clone(pool),
+            stderr_logger: stderr_logger
+                .take()
+                .expect("The stderr logger is only used once"),
+            timed_out: Arc::clone(&timed_out),
+            buffer: Vec::new(),
+            position: 0,
+            received_records: false,
+            lease: lease.take(),
+        };
+
+        let (error, may_retry) = match sent {
+            Err(error) => (error, true),
+            Ok(()) => match reader.wait_for_output() {
+                Ok(()) => return Ok(reader),
+                Err(error) => {
+                    let closed_unanswered = !reader.received_records
+                        && matches!(
+                            error.kind(),
+                            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+                        );
+                    (error, closed_unanswered && idempotent)
+                }
+            },
+        };
+
+        if is_timeout(&error) {
+            return Err(CgiError::Timeout);
+        }
+        if !reused || !may_retry {
+            return Err(CgiError::ConnectionFailed(error));
+        }
+
+        debug!("Reused FastCGI connection failed, reconnecting: {}", error);
+        pool.clear();
+        stderr_logger = Some(reader.stderr_logger);
+        lease = reader.lease;
+        connection = pool.connect().map_err(CgiError::ConnectionFailed)?;
+        reused = false;
+    }
+}
+
+impl RequestHandler<Vec<u8>> for FastCgiRequestHandler {
+    /// Handles an incoming request. If the requested path is under the
+    /// application path, forwards the request to the FastCGI application and
+    /// returns its response. Otherwise returns a `None` value so that the
+    /// next handler can try to process the request.
+    ///
+    fn handle_request(
+        &self,
+        stream: &TcpStream,
+        request: &Request<Vec<u8>>,
+    ) -> Option<Response<ResponseBody>> {
+        let (script_name, path_info) = split_path(&self.path, request.uri().path())?;
+
+        match self.forward_request(stream, request, script_name, path_info) {
+            Err(error) => {
+                warn!("FastCGI request to {} failed: {}", script_name, error);
+                debug!("FastCGI error: {:?}", error);
+                Some(error.to_response(self.settings.detailed_errors))
+            }
+            Ok(response) => Some(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{net::TcpListener, os::unix::net::UnixListener, sync::atomic::AtomicUsize, thread};
+
+    use http::StatusCode;
+
+    use crate::http_server::request::{
+        fastcgi_request::fastcgi_protocol::{decode_params, write_record},
+        test_support::{client_stream, run_request},
+    };
+
+    /// Serves FastCGI requests over a single connection, answering with the
+    /// parameters and body it received, until the connection is closed.
+    ///
+    fn serve_connection<S: Read + Write>(mut connection: S) {
+        loop {
+            let mut params = Vec::new();
+            let mut body = Vec::new();
+
+            loop {
+                let record = match read_record(&mut connection) {
+                    Err(_) => return,
+                    Ok(record) => record,
+                };
+
+                match record.record_type {
+                    RecordType::Params => params.extend_from_slice(&record.content),
+                    RecordType::Stdin if record.content.is_empty() => break,
+                    RecordType::Stdin => body.extend_from_slice(&record.content),
+                    _ => (),
+                }
+            }
+
+            let params = decode_params(&params).unwrap();
+            let param = |name: &str| {
+                params
+                    .iter()
+                    .find(|(param_name, _)| param_name == name)
+                    .map_or(String::new(), |(_, value)| value.clone())
+            };
+            let output = format!(
+                "Content-Type: text/plain\r\n\r\n{} {}{} {} {}",
+                param("REQUEST_METHOD"),
+                param("SCRIPT_NAME"),
+                param("PATH_INFO"),
+                param("QUERY_STRING"),
+                String::from_utf8_lossy(&body)
+            );
+
+            write_record(
+                &mut connection,
+                RecordType::Stderr,
+                REQUEST_ID,
+                b"warning\n",
+            )
+            .unwrap();
+            write_stream(
+                &mut connection,
+                RecordType::Stdout,
+                REQUEST_ID,
+                output.as_bytes(),
+            )
+            .unwrap();
+            write_record(
+                &mut connection,
+                RecordType::EndRequest,
+                REQUEST_ID,
+                &[0, 0, 0, 0, FCGI_REQUEST_COMPLETE, 0, 0, 0],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn requests_are_forwarded_over_a_pooled_tcp_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted_connections = Arc::new(AtomicUsize::new(0));
+        let accepted_connections_clone = Arc::clone(&accepted_connections);
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                accepted_connections_clone.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || serve_connection(connection.unwrap()));
+            }
+        });
+
+        let handler = FastCgiRequestHandler::new(
+            String::from("app"),
+            BackendAddress::parse(&address),
+            String::from("public_html"),
+            FastCgiSettings::default(),
+        );
+
+        assert_eq!(
+            run_request(&handler, "/app/users/42?page=2", b"name=test"),
+            (
+                StatusCode::OK,
+                String::from("POST /app/users/42 page=2 name=test")
+            )
+        );
+        assert_eq!(
+            run_request(&handler, "/app", b""),
+            (StatusCode::OK, String::from("POST /app  "))
+        );
+        assert_eq!(accepted_connections.load(Ordering::SeqCst), 1);
+
+        let request = Request::builder()
+            .uri("/application")
+            .body(Vec::new())
+            .unwrap();
+        assert!(handler.handle_request(&client_stream(), &request).is_none());
+    }
+
+    #[test]
+    fn non_idempotent_request_is_not_sent_twice() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let received_requests = Arc::new(AtomicUsize::new(0));
+        let received_requests_clone = Arc::clone(&received_requests);
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                let mut connection = connection.unwrap();
+                // Answers the first request, then closes the connection
+                // without answering the next one.
+                loop {
+                    match read_record(&mut connection) {
+                        Err(_) => break,
+                        Ok(record)
+                            if record.record_type == RecordType::Stdin
+                                && record.content.is_empty() =>
+                        {
+                            if received_requests_clone.fetch_add(1, Ordering::SeqCst) > 0 {
+                                break;
+                            }
+                            write_stream(
+                                &mut connection,
+                                RecordType::Stdout,
+                                REQUEST_ID,
+                                b"Content-Type: text/plain\r\n\r\nok",
+                            )
+                            .unwrap();
+                            write_record(
+                                &mut connection,
+                                RecordType::EndRequest,
+                                REQUEST_ID,
+                                &[0, 0, 0, 0, FCGI_REQUEST_COMPLETE, 0, 0, 0],
+                            )
+                            .unwrap();
+                        }
+                        Ok(_) => (),
+                    }
+                }
+            }
+        });
+
+        let handler = FastCgiRequestHandler::new(
+            String::from(""),
+            BackendAddress::parse(&address),
+            String::from("public_html"),
+            FastCgiSettings::default(),
+        );
+
+        assert_eq!(run_request(&handler, "/", b"").0, StatusCode::OK);
+        assert_eq!(run_request(&handler, "/", b"").0, StatusCode::BAD_GATEWAY);
+        assert_eq!(received_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn requests_are_forwarded_over_a_unix_socket() {
+        let socket_path = std::env::temp_dir().join(format!("{}-fastcgi.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        thread::spawn(move || serve_connection(listener.accept().unwrap().0));
+
+        let handler = FastCgiRequestHandler::new(
+            String::from(""),
+            BackendAddress::parse(&format!("unix:{}", socket_path.display())),
+            String::from("public_html"),
+            FastCgiSettings::default(),
+        );
+
+        assert_eq!(
+            run_request(&handler, "/index?a=1", b""),
+            (StatusCode::OK, String::from("POST /index a=1 "))
+        );
+        std::fs::remove_file(socket_path).unwrap();
+    }
+
+    #[test]
+    fn unreachable_application_is_a_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let handler = FastCgiRequestHandler::new(
+            String::from("app"),
+            BackendAddress::parse(&address),
+            String::from("public_html"),
+            FastCgiSettings::default(),
+        );
+
+        assert_eq!(
+            run_request(&handler, "/app", b"").0,
+            StatusCode::BAD_GATEWAY
+        );
+    }
+}
@@ -0,0 +1,680 @@
+use std::{
+    collections::hash_map::RandomState,
+    fs::{self, DirBuilder},
+    hash::{BuildHasher, Hasher},
+    io, mem,
+    os::{
+        fd::OwnedFd,
+        unix::{fs::DirBuilderExt, net::UnixListener},
+    },
+    path::{Path, PathBuf},
+    process::{Child, Stdio},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+#[cfg(target_os = "linux")]
+use std::os::unix::process::CommandExt;
+
+use log::{debug, info, warn, Level};
+
+use crate::http_server::request::{
+    backend::BackendAddress,
+    cgi_request::{
+        cgi_error::CgiError,
+        cgi_error_log::{CgiErrorLog, CgiStderrLogger},
+        cgi_handler::server_environment_variables,
+        cgi_process::{CgiProgram, CgiResourceLimits},
+    },
+};
+
+const DEFAULT_MIN_PROCESSES: usize = 1;
+const DEFAULT_MAX_PROCESSES: usize = 4;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval at which the supervisor checks on the application processes.
+const SUPERVISION_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tunable options for the FastCGI application processes started by the
+/// server.
+pub struct FastCgiProcessSettings {
+    /// Number of processes kept running, even when they are idle.
+    pub min_processes: usize,
+    /// Maximum number of processes running at the same time. Requests
+    /// arriving while all of them are busy wait for one to be released.
+    pub max_processes: usize,
+    /// Number of requests after which a process is replaced by a new one, if
+    /// any.
+    pub max_requests_per_process: Option<u64>,
+    /// Time after which idle processes above `min_processes` are stopped.
+    pub idle_timeout: Duration,
+    /// Time given to processes to exit once asked to (with `SIGTERM`),
+    /// after which they are killed.
+    pub shutdown_timeout: Duration,
+    /// Resource limits applied to each process. Since processes handle many
+    /// requests, a CPU time limit would eventually stop any of them.
+    pub resource_limits: CgiResourceLimits,
+    /// Folder in which the manager creates a private folder (only accessible
+    /// to the user running the server) holding the Unix domain sockets the
+    /// processes listen on.
+    pub socket_folder: PathBuf,
+    /// Level at which the stderr output of the processes is logged.
+    pub stderr_log_level: Level,
+    /// File to which the stderr output of the processes is also appended, if
+    /// any.
+    pub error_log: Option<Arc<CgiErrorLog>>,
+}
+
+impl Default for FastCgiProcessSettings {
+    fn default() -> FastCgiProcessSettings {
+        FastCgiProcessSettings {
+            min_processes: DEFAULT_MIN_PROCESSES,
+            max_processes: DEFAULT_MAX_PROCESSES,
+            max_requests_per_process: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            resource_limits: CgiResourceLimits::default(),
+            socket_folder: std::env::temp_dir(),
+            stderr_log_level: Level::Warn,
+            error_log: None,
+        }
+    }
+}
+
+/// Application process, listening on its own Unix domain socket.
+struct FastCgiProcess {
+    id: usize,
+    child: Child,
+    socket_path: PathBuf,
+    handled_requests: u64,
+    busy: bool,
+    last_used: Instant,
+}
+
+impl FastCgiProcess {
+    /// Returns whether the process has exited (reaping it if so).
+    ///
+    fn has_exited(&mut self) -> bool {
+        match self.child.try_wait() {
+            Ok(None) => false,
+            Ok(Some(exit_status)) => {
+                debug!("FastCGI process {} exited with {}", self.id, exit_status);
+                true
+            }
+            Err(error) => {
+                debug!("Error checking FastCGI process {}: {:?}", self.id, error);
+                true
+            }
+        }
+    }
+
+    /// Removes the socket of a process which has exited.
+    ///
+    fn remove_socket(&self) {
+        if let Err(error) = fs::remove_file(&self.socket_path) {
+            debug!("Error removing FastCGI socket: {:?}", error);
+        }
+    }
+}
+
+/// Process which was asked to exit, and is killed if it is still running
+/// past its deadline.
+struct StoppingProcess {
+    process: FastCgiProcess,
+    deadline: Instant,
+}
+
+/// Processes handled by the manager.
+#[derive(Default)]
+struct ProcessList {
+    running: Vec<FastCgiProcess>,
+    stopping: Vec<StoppingProcess>,
+    /// Number of requests waiting for a process to be available.
+    waiting_requests: usize,
+    next_id: usize,
+    shutting_down: bool,
+}
+
+/// State shared between the manager, its supervisor thread and the leases
+/// it hands out.
+struct ManagerState {
+    program: CgiProgram,
+    settings: FastCgiProcessSettings,
+    /// Private folder holding the sockets of the processes.
+    socket_folder: PathBuf,
+    processes: Mutex<ProcessList>,
+    /// Notified whenever a process is released or started, or the manager
+    /// shuts down.
+    processes_changed: Condvar,
+    /// Notified when requests start waiting for a process, so that the
+    /// supervisor starts new ones, or when the manager shuts down.
+    supervisor_wakeup: Condvar,
+}
+
+impl ManagerState {
+    fn lock(&self) -> MutexGuard<'_, ProcessList> {
+        self.processes
+            .lock()
+            .expect("Failed to acquire the FastCGI process list mutex")
+    }
+
+    /// Starts a new process, which gets the socket it must accept
+    /// connections on as its standard input, as required by section 2.2 of
+    /// the FastCGI specification.
+    ///
+    fn start_process(&self, processes: &mut ProcessList) -> io::Result<()> {
+        let id = processes.next_id;
+        processes.next_id += 1;
+
+        let socket_path = self.socket_folder.join(format!("fastcgi-{id}.sock"));
+        let listener = UnixListener::bind(&socket_path)?;
+
+        let mut command = self.program.prepare_command(
+            &server_environment_variables(),
+            self.settings.resource_limits,
+        );
+        command
+            .stdin(Stdio::from(OwnedFd::from(listener)))
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        // Processes are asked to exit if the server dies without stopping
+        // them. The signal is actually sent when the thread which started
+        // them exits, which is why they are only started by the supervisor
+        // thread, living as long as the manager.
+        // SAFETY: the closure runs in the forked child and only calls
+        // `prctl`, which is async-signal-safe.
+        #[cfg(target_os = "linux")]
+        unsafe {
+            command.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let spawned = command.spawn();
+        let mut child = match spawned {
+            Err(error) => {
+                let _ = fs::remove_file(&socket_path);
+                return Err(error);
+            }
+            Ok(child) => child,
+        };
+
+        if let Some(stderr) = child.stderr.take() {
+            CgiStderrLogger {
+                script: self.program.script_path.to_string_lossy().to_string(),
+                request_id: String::from("-"),
+                client_address: String::from("-"),
+                level: self.settings.stderr_log_level,
+                error_log: self.settings.error_log.clone(),
+            }
+            .spawn(stderr);
+        }
+
+        info!(
+            "Started FastCGI process {} (pid {}) for {:?}",
+            id,
+            child.id(),
+            self.program.script_path
+        );
+        processes.running.push(FastCgiProcess {
+            id,
+            child,
+            socket_path,
+            handled_requests: 0,
+            busy: false,
+            last_used: Instant::now(),
+        });
+
+        Ok(())
+    }
+
+    /// Asks a process to exit. It is reaped later by `reap_stopping`.
+    ///
+    fn stop_process(&self, processes: &mut ProcessList, process: FastCgiProcess) {
+        debug!("Stopping FastCGI process {}", process.id);
+        // SAFETY: `kill` has no memory safety requirements.
+        unsafe {
+            libc::kill(process.child.id() as libc::pid_t, libc::SIGTERM);
+        }
+
+        processes.stopping.push(StoppingProcess {
+            process,
+            deadline: Instant::now() + self.settings.shutdown_timeout,
+        });
+    }
+
+    /// Reaps the processes which exited after being asked to, and kills the
+    /// ones which are still running past their deadline.
+    ///
+    fn reap_stopping(&self, processes: &mut ProcessList) {
+        let now = Instant::now();
+
+        processes.stopping.retain_mut(|stopping| {
+            if stopping.process.has_exited() {
+                stopping.process.remove_socket();
+                return false;
+            }
+
+            if now >= stopping.deadline {
+                warn!(
+                    "FastCGI process {} didn't exit in time, killing it",
+                    stopping.process.id
+                );
+                if let Err(error) = stopping.process.child.kill() {
+                    debug!("Error killing FastCGI process: {:?}", error);
+                }
+            }
+            true
+        });
+    }
+
+    /// Brings the processes back in line with the settings: crashed
+    /// processes are removed, processes idle for too long are stopped, and
+    /// new processes are started until there are at least `min_processes`,
+    /// or as many as there are busy processes and waiting requests (up to
+    /// `max_processes`).
+    ///
+    fn supervise(&self, processes: &mut ProcessList) {
+        let mut index = 0;
+        while index < processes.running.len() {
+            let process = &mut processes.running[index];
+            if process.has_exited() {
+                warn!("FastCGI process {} exited unexpectedly", process.id);
+                process.remove_socket();
+                processes.running.remove(index);
+            } else if !process.busy
+                && process.last_used.elapsed() >= self.settings.idle_timeout
+                && processes.running.len() > self.settings.min_processes
+            {
+                let process = processes.running.remove(index);
+                self.stop_process(processes, process);
+            } else {
+                index += 1;
+            }
+        }
+
+        let busy_processes = processes
+            .running
+            .iter()
+            .filter(|process| process.busy)
+            .count();
+        let wanted_processes = (busy_processes + processes.waiting_requests)
+            .clamp(self.settings.min_processes, self.settings.max_processes);
+
+        let mut started = false;
+        while processes.running.len() < wanted_processes {
+            if let Err(error) = self.start_process(processes) {
+                warn!("Failed to start FastCGI process: {}", error);
+                break;
+            }
+            started = true;
+        }
+        if started {
+            self.processes_changed.notify_all();
+        }
+
+        self.reap_stopping(processes);
+    }
+
+    /// Makes a process available again once a request is over, replacing it
+    /// if it has handled as many requests as it may.
+    ///
+    fn release(&self, process_id: usize) {
+        let mut processes = self.lock();
+
+        if let Some(index) = processes.running.iter().position(|p| p.id == process_id) {
+            let process = &mut processes.running[index];
+            process.busy = false;
+            process.last_used = Instant::now();
+            process.handled_requests += 1;
+
+            if self
+                .settings
+                .max_requests_per_process
+                .is_some_and(|max_requests| process.handled_requests >= max_requests)
+            {
+                info!(
+                    "FastCGI process {} handled {} requests, replacing it",
+                    process.id, process.handled_requests
+                );
+                let process = processes.running.remove(index);
+                self.stop_process(&mut processes, process);
+            }
+        }
+
+        self.processes_changed.notify_all();
+    }
+}
+
+/// Exclusive use of an application process for the duration of a request.
+/// The process is released when the lease is dropped.
+pub struct FastCgiProcessLease {
+    state: Arc<ManagerState>,
+    process_id: usize,
+    address: BackendAddress,
+}
+
+impl FastCgiProcessLease {
+    /// Returns the address the process listens on.
+    ///
+    pub fn address(&self) -> &BackendAddress {
+        &self.address
+    }
+}
+
+impl Drop for FastCgiProcessLease {
+    fn drop(&mut self) {
+        self.state.release(self.process_id);
+    }
+}
+
+/// Starts and supervises a pool of processes running a FastCGI application
+/// (like Apache's `mod_fcgid`). The number of processes grows with the load,
+/// up to `max_processes`, and shrinks back to `min_processes` once they are
+/// idle. Crashed processes are replaced, and all of them are stopped when the
+/// manager is dropped, which happens once the request handler owning it
+/// (and the `ThreadPool` running the connections) is gone.
+pub struct FastCgiProcessManager {
+    state: Arc<ManagerState>,
+    supervisor: Option<JoinHandle<()>>,
+}
+
+/// Creates a folder only accessible to the current user in `parent`, with a
+/// random name so that other users can't create it beforehand (or replace
+/// the sockets it will hold). Names which are already taken are skipped.
+///
+fn create_private_folder(parent: &Path) -> io::Result<PathBuf> {
+    loop {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        let folder = parent.join(format!(
+            "rust_web_cgi-{}-{:016x}",
+            std::process::id(),
+            hasher.finish()
+        ));
+
+        match DirBuilder::new().mode(0o700).create(&folder) {
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error),
+            Ok(()) => return Ok(folder),
+        }
+    }
+}
+
+impl FastCgiProcessManager {
+    /// Starts the supervisor thread, which starts the first processes of the
+    /// given program right away.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function panics if `max_processes` is zero or lower than
+    /// `min_processes`, or if the private folder holding the sockets can't
+    /// be created.
+    pub fn new(program: CgiProgram, settings: FastCgiProcessSettings) -> FastCgiProcessManager {
+        assert!(settings.max_processes > 0);
+        assert!(settings.max_processes >= settings.min_processes);
+
+        let socket_folder = create_private_folder(&settings.socket_folder)
+            .expect("Failed to create the FastCGI socket folder");
+
+        let state = Arc::new(ManagerState {
+            program,
+            settings,
+            socket_folder,
+            processes: Mutex::new(ProcessList::default()),
+            processes_changed: Condvar::new(),
+            supervisor_wakeup: Condvar::new(),
+        });
+
+        let supervisor_state = Arc::clone(&state);
+        let supervisor = thread::spawn(move || {
+            let mut processes = supervisor_state.lock();
+            while !processes.shutting_down {
+                supervisor_state.supervise(&mut processes);
+                processes = supervisor_state
+                    .supervisor_wakeup
+                    .wait_timeout(processes, SUPERVISION_INTERVAL)
+                    .expect("Failed to acquire the FastCGI process list mutex")
+                    .0;
+            }
+        });
+
+        FastCgiProcessManager {
+            state,
+            supervisor: Some(supervisor),
+        }
+    }
+
+    /// Returns the path to the script run by the processes.
+    ///
+    pub fn script_path(&self) -> &Path {
+        &self.state.program.script_path
+    }
+
+    /// Picks an idle process to handle a request. If all of them are busy,
+    /// waits for at most `timeout` for a process to be released, or to be
+    /// started by the supervisor if there are fewer than `max_processes`.
+    /// Processes are never started from here, since this runs on the
+    /// connection threads (see `ManagerState::start_process`).
+    ///
+    pub fn acquire(&self, timeout: Duration) -> Result<FastCgiProcessLease, CgiError> {
+        let deadline = Instant::now() + timeout;
+        let mut processes = self.state.lock();
+
+        loop {
+            if processes.shutting_down {
+                return Err(CgiError::ConnectionFailed(io::Error::other(
+                    "FastCGI process manager is shutting down",
+                )));
+            }
+
+            let idle_process = processes
+                .running
+                .iter_mut()
+                .position(|process| !process.busy && !process.has_exited());
+            if let Some(index) = idle_process {
+                let process = &mut processes.running[index];
+                process.busy = true;
+                return Ok(FastCgiProcessLease {
+                    state: Arc::clone(&self.state),
+                    process_id: process.id,
+                    address: BackendAddress::Unix(process.socket_path.clone()),
+                });
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("All FastCGI processes are busy");
+                return Err(CgiError::Timeout);
+            }
+
+            processes.waiting_requests += 1;
+            if processes.running.len() < self.state.settings.max_processes {
+                self.state.supervisor_wakeup.notify_one();
+            }
+            processes = self
+                .state
+                .processes_changed
+                .wait_timeout(processes, deadline - now)
+                .expect("Failed to acquire the FastCGI process list mutex")
+                .0;
+            processes.waiting_requests -= 1;
+        }
+    }
+}
+
+impl Drop for FastCgiProcessManager {
+    /// Stops the supervisor, then asks all processes to exit and waits for
+    /// them, killing the ones still running after the shutdown timeout. The
+    /// socket folder is removed once they are all gone.
+    ///
+    fn drop(&mut self) {
+        self.state.lock().shutting_down = true;
+        self.state.supervisor_wakeup.notify_all();
+        self.state.processes_changed.notify_all();
+        if let Some(supervisor) = self.supervisor.take() {
+            if supervisor.join().is_err() {
+                warn!("FastCGI supervisor thread panicked");
+            }
+        }
+
+        let mut processes = self.state.lock();
+        for process in mem::take(&mut processes.running) {
+            self.state.stop_process(&mut processes, process);
+        }
+
+        loop {
+            self.state.reap_stopping(&mut processes);
+            if processes.stopping.is_empty() {
+                break;
+            }
+
+            drop(processes);
+            thread::sleep(Duration::from_millis(50));
+            processes = self.state.lock();
+        }
+        if let Err(error) = fs::remove_dir_all(&self.state.socket_folder) {
+            debug!("Error removing FastCGI socket folder: {:?}", error);
+        }
+        info!("FastCGI processes for {:?} stopped", self.script_path());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    fn sample_manager(name: &str, settings: FastCgiProcessSettings) -> FastCgiProcessManager {
+        let script_path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::write(&script_path, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        FastCgiProcessManager::new(
+            CgiProgram {
+                script_path,
+                interpreter: None,
+            },
+            settings,
+        )
+    }
+
+    fn process_ids(manager: &FastCgiProcessManager) -> Vec<u32> {
+        let processes = manager.state.lock();
+        processes
+            .running
+            .iter()
+            .map(|process| process.child.id())
+            .collect()
+    }
+
+    #[test]
+    fn processes_scale_between_min_and_max() {
+        let manager = sample_manager(
+            "scaling_fastcgi.sh",
+            FastCgiProcessSettings {
+                min_processes: 1,
+                max_processes: 2,
+                idle_timeout: Duration::ZERO,
+                ..FastCgiProcessSettings::default()
+            },
+        );
+
+        let first = manager.acquire(Duration::from_secs(1)).unwrap();
+        let second = manager.acquire(Duration::from_secs(1)).unwrap();
+        assert_ne!(first.address(), second.address());
+        assert!(matches!(
+            manager.acquire(Duration::from_millis(100)),
+            Err(CgiError::Timeout)
+        ));
+
+        drop(first);
+        drop(manager.acquire(Duration::from_secs(1)).unwrap());
+        drop(second);
+
+        thread::sleep(SUPERVISION_INTERVAL * 3);
+        assert_eq!(process_ids(&manager).len(), 1);
+        fs::remove_file(manager.script_path()).unwrap();
+    }
+
+    #[test]
+    fn processes_outlive_the_threads_requesting_them() {
+        let manager = Arc::new(sample_manager(
+            "threads_fastcgi.sh",
+            FastCgiProcessSettings {
+                max_processes: 2,
+                ..FastCgiProcessSettings::default()
+            },
+        ));
+
+        let lease = manager.acquire(Duration::from_secs(1)).unwrap();
+        let thread_manager = Arc::clone(&manager);
+        thread::spawn(move || drop(thread_manager.acquire(Duration::from_secs(1)).unwrap()))
+            .join()
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let mut processes = manager.state.lock();
+        assert_eq!(processes.running.len(), 2);
+        assert!(processes
+            .running
+            .iter_mut()
+            .all(|process| !process.has_exited()));
+        drop(processes);
+        drop(lease);
+        fs::remove_file(manager.script_path()).unwrap();
+    }
+
+    #[test]
+    fn processes_are_replaced_and_stopped() {
+        let manager = sample_manager(
+            "replaced_fastcgi.sh",
+            FastCgiProcessSettings {
+                max_processes: 1,
+                max_requests_per_process: Some(1),
+                shutdown_timeout: Duration::from_secs(1),
+                ..FastCgiProcessSettings::default()
+            },
+        );
+
+        let lease = manager.acquire(Duration::from_secs(1)).unwrap();
+        let first_process = process_ids(&manager);
+        let socket_path = match lease.address() {
+            BackendAddress::Unix(socket_path) => socket_path.clone(),
+            address => panic!("Unexpected process address {:?}", address),
+        };
+        assert!(socket_path.exists());
+        let socket_folder = socket_path.parent().unwrap().to_path_buf();
+        assert_eq!(
+            fs::metadata(&socket_folder).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        drop(lease);
+
+        let lease = manager.acquire(Duration::from_secs(1)).unwrap();
+        let second_process = process_ids(&manager);
+        assert_ne!(first_process, second_process);
+        drop(lease);
+
+        let script_path = manager.script_path().to_path_buf();
+        drop(manager);
+        // SAFETY: `kill` has no memory safety requirements.
+        let still_running = unsafe { libc::kill(second_process[0] as libc::pid_t, 0) } == 0;
+        assert!(!still_running);
+        assert!(!socket_path.exists());
+        assert!(!socket_folder.exists());
+        fs::remove_file(script_path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use std::io::{self, Read, Write};
+
+/// Version of the FastCGI protocol spoken by the server.
+pub const FCGI_VERSION_1: u8 = 1;
+
+/// Largest amount of data carried by a single record.
+const MAX_CONTENT_LENGTH: usize = 65535;
+/// Records are padded so that their length is a multiple of this value.
+const RECORD_ALIGNMENT: usize = 8;
+const HEADER_LENGTH: usize = 8;
+
+/// Role asking the application to generate an HTTP response.
+pub const FCGI_RESPONDER: u16 = 1;
+/// Flag asking the application to keep the connection open after the request.
+pub const FCGI_KEEP_CONN: u8 = 1;
+
+/// Protocol status of an `END_REQUEST` record for a request which completed
+/// normally.
+pub const FCGI_REQUEST_COMPLETE: u8 = 0;
+
+/// Types of FastCGI records, as listed in section 8 of the FastCGI
+/// specification.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecordType {
+    BeginRequest,
+    AbortRequest,
+    EndRequest,
+    Params,
+    Stdin,
+    Stdout,
+    Stderr,
+    Data,
+    GetValues,
+    GetValuesResult,
+    UnknownType,
+    /// Record type not defined by the specification.
+    Other(u8),
+}
+
+impl From<u8> for RecordType {
+    fn from(value: u8) -> RecordType {
+        match value {
+            1 => RecordType::BeginRequest,
+            2 => RecordType::AbortRequest,
+            3 => RecordType::EndRequest,
+            4 => RecordType::Params,
+            5 => RecordType::Stdin,
+            6 => RecordType::Stdout,
+            7 => RecordType::Stderr,
+            8 => RecordType::Data,
+            9 => RecordType::GetValues,
+            10 => RecordType::GetValuesResult,
+            11 => RecordType::UnknownType,
+            value => RecordType::Other(value),
+        }
+    }
+}
+
+impl From<RecordType> for u8 {
+    fn from(record_type: RecordType) -> u8 {
+        match record_type {
+            RecordType::BeginRequest => 1,
+            RecordType::AbortRequest => 2,
+            RecordType::EndRequest => 3,
+            RecordType::Params => 4,
+            RecordType::Stdin => 5,
+            RecordType::Stdout => 6,
+            RecordType::Stderr => 7,
+            RecordType::Data => 8,
+            RecordType::GetValues => 9,
+            RecordType::GetValuesResult => 10,
+            RecordType::UnknownType => 11,
+            RecordType::Other(value) => value,
+        }
+    }
+}
+
+/// A single FastCGI record, without its padding.
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub record_type: RecordType,
+    pub request_id: u16,
+    pub content: Vec<u8>,
+}
+
+/// Body of an `END_REQUEST` record.
+#[derive(Debug, PartialEq)]
+pub struct EndRequest {
+    /// Exit status of the application for the request.
+    pub app_status: u32,
+    /// Whether the request was completed or rejected by the application.
+    pub protocol_status: u8,
+}
+
+impl EndRequest {
+    /// Parses the content of an `END_REQUEST` record.
+    ///
+    pub fn parse(content: &[u8]) -> io::Result<EndRequest> {
+        if content.len() < 5 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "END_REQUEST record is too short",
+            ));
+        }
+
+        Ok(EndRequest {
+            app_status: u32::from_be_bytes([content[0], content[1], content[2], content[3]]),
+            protocol_status: content[4],
+        })
+    }
+}
+
+/// Writes a single record, which can hold at most 65535 bytes of content.
+///
+pub fn write_record<W: Write>(
+    writer: &mut W,
+    record_type: RecordType,
+    request_id: u16,
+    content: &[u8],
+) -> io::Result<()> {
+    let content_length = u16::try_from(content.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record content is too long"))?;
+    let padding_length = (RECORD_ALIGNMENT - content.len() % RECORD_ALIGNMENT) % RECORD_ALIGNMENT;
+
+    let request_id = request_id.to_be_bytes();
+    let content_length = content_length.to_be_bytes();
+    writer.write_all(&[
+        FCGI_VERSION_1,
+        record_type.into(),
+        request_id[0],
+        request_id[1],
+        content_length[0],
+        content_length[1],
+        padding_length as u8,
+        0,
+    ])?;
+    writer.write_all(content)?;
+    writer.write_all(&[0; RECORD_ALIGNMENT][..padding_length])
+}
+
+/// Writes a whole stream (such as `PARAMS` or `STDIN`), split into as many
+/// records as needed and followed by the empty record which ends it.
+///
+pub fn write_stream<W: Write>(
+    writer: &mut W,
+    record_type: RecordType,
+    request_id: u16,
+    data: &[u8],
+) -> io::Result<()> {
+    for chunk in data.chunks(MAX_CONTENT_LENGTH) {
+        write_record(writer, record_type, request_id, chunk)?;
+    }
+
+    write_record(writer, record_type, request_id, &[])
+}
+
+/// Writes the `BEGIN_REQUEST` record starting a request with the given role.
+///
+pub fn write_begin_request<W: Write>(
+    writer: &mut W,
+    request_id: u16,
+    role: u16,
+    flags: u8,
+) -> io::Result<()> {
+    let role = role.to_be_bytes();
+    write_record(
+        writer,
+        RecordType::BeginRequest,
+        request_id,
+        &[role[0], role[1], flags, 0, 0, 0, 0, 0],
+    )
+}
+
+/// Reads a single record, discarding its padding.
+///
+pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Record> {
+    let mut header = [0; HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+
+    if header[0] != FCGI_VERSION_1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported FastCGI version {}", header[0]),
+        ));
+    }
+
+    let content_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding_length = header[6] as usize;
+    let mut content = vec![0; content_length + padding_length];
+    reader.read_exact(&mut content)?;
+    content.truncate(content_length);
+
+    Ok(Record {
+        record_type: RecordType::from(header[1]),
+        request_id: u16::from_be_bytes([header[2], header[3]]),
+        content,
+    })
+}
+
+/// Helper function which encodes the length of a name or value: lengths
+/// below 128 take a single byte, while longer ones take four bytes with the
+/// highest bit set.
+///
+fn encode_length(length: usize, encoded: &mut Vec<u8>) {
+    if length < 128 {
+        encoded.push(length as u8);
+    } else {
+        encoded.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Encodes name-value pairs (such as the request parameters) into the format
+/// carried by `PARAMS` records.
+///
+pub fn encode_params<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(params: I) -> Vec<u8> {
+    let mut encoded = Vec::new();
+
+    for (name, value) in params {
+        encode_length(name.len(), &mut encoded);
+        encode_length(value.len(), &mut encoded);
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+
+    encoded
+}
+
+/// Decodes name-value pairs encoded by `encode_params`.
+///
+pub fn decode_params(mut data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let invalid_params = || io::Error::new(io::ErrorKind::InvalidData, "invalid FastCGI params");
+    let read_length = |data: &mut &[u8]| -> io::Result<usize> {
+        match data {
+            [length, rest @ ..] if *length < 128 => {
+                *data = rest;
+                Ok(*length as usize)
+            }
+            [first, second, third, fourth, rest @ ..] => {
+                let length = u32::from_be_bytes([*first, *second, *third, *fourth]) & 0x7fff_ffff;
+                *data = rest;
+                Ok(length as usize)
+            }
+            _ => Err(invalid_params()),
+        }
+    };
+
+    let mut params = Vec::new();
+    while !data.is_empty() {
+        let name_length = read_length(&mut data)?;
+        let value_length = read_length(&mut data)?;
+        if data.len() < name_length + value_length {
+            return Err(invalid_params());
+        }
+
+        let (name, rest) = data.split_at(name_length);
+        let (value, rest) = rest.split_at(value_length);
+        params.push((
+            String::from_utf8_lossy(name).to_string(),
+            String::from_utf8_lossy(value).to_string(),
+        ));
+        data = rest;
+    }
+
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_are_encoded_and_decoded() {
+        let long_value = "x".repeat(300);
+        let encoded = encode_params([("SCRIPT_NAME", "/app"), ("HTTP_COOKIE", &long_value[..])]);
+
+        assert_eq!(&encoded[..2], &[11, 4]);
+        assert_eq!(
+            decode_params(&encoded).unwrap(),
+            [
+                (String::from("SCRIPT_NAME"), String::from("/app")),
+                (String::from("HTTP_COOKIE"), long_value.clone()),
+            ]
+        );
+    }
+
+    #[test]
+    fn streams_are_split_into_padded_records() {
+        let data = vec![7; MAX_CONTENT_LENGTH + 3];
+        let mut encoded = Vec::new();
+        write_stream(&mut encoded, RecordType::Stdin, 1, &data).unwrap();
+
+        let mut reader = &encoded[..];
+        let first = read_record(&mut reader).unwrap();
+        let second = read_record(&mut reader).unwrap();
+        let last = read_record(&mut reader).unwrap();
+
+        assert_eq!(first.record_type, RecordType::Stdin);
+        assert_eq!(first.content.len(), MAX_CONTENT_LENGTH);
+        assert_eq!(second.content, [7, 7, 7]);
+        assert!(last.content.is_empty());
+        assert!(reader.is_empty());
+        assert_eq!(encoded.len() % RECORD_ALIGNMENT, 0);
+    }
+}
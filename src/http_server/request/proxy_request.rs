@@ -0,0 +1,3 @@
+pub mod proxy_balancer;
+pub mod proxy_handler;
+pub mod proxy_response;
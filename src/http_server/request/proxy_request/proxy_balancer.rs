@@ -0,0 +1,523 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{self, BufReader, Write},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+
+use log::{debug, info, warn};
+
+use crate::http_server::request::{
+    backend::{BackendAddress, BackendStream},
+    proxy_request::proxy_response::read_upstream_response,
+};
+
+const DEFAULT_MAX_FAILURES: usize = 3;
+const DEFAULT_FAILURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of points each upstream server gets on the hash ring used by the
+/// `ClientIpHash` strategy. More points spread the clients more evenly.
+const VIRTUAL_NODES: usize = 64;
+
+/// How requests are spread across the upstream servers of a route.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BalancingStrategy {
+    /// Each upstream server gets a request in turn.
+    RoundRobin,
+    /// Requests go to the upstream server handling the fewest requests.
+    LeastConnections,
+    /// Requests from a given client IP address always go to the same
+    /// upstream server (consistent hashing), unless it is down. Clients of
+    /// a server going down are spread across the other ones, while the
+    /// other clients keep their server.
+    ClientIpHash,
+}
+
+/// Request periodically sent to each upstream server to check whether it
+/// is healthy, which is the case if it answers with a 2xx or 3xx status.
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    /// Path requested with a GET request.
+    pub path: String,
+    /// Time between two checks of a server.
+    pub interval: Duration,
+    /// Maximum time spent waiting for the server to answer.
+    pub timeout: Duration,
+}
+
+/// Tunable options for the choice of the upstream server of each request.
+#[derive(Clone, Debug)]
+pub struct BalancerSettings {
+    pub strategy: BalancingStrategy,
+    /// Number of consecutive failed requests (connection errors, invalid
+    /// responses or timeouts) after which an upstream server is skipped.
+    pub max_failures: usize,
+    /// How long an upstream server which failed too many requests is
+    /// skipped, after which it gets requests again.
+    pub failure_timeout: Duration,
+    /// Active health checks, if any. Upstream servers failing them are
+    /// skipped until they pass them again.
+    pub health_check: Option<HealthCheck>,
+}
+
+impl Default for BalancerSettings {
+    fn default() -> BalancerSettings {
+        BalancerSettings {
+            strategy: BalancingStrategy::RoundRobin,
+            max_failures: DEFAULT_MAX_FAILURES,
+            failure_timeout: DEFAULT_FAILURE_TIMEOUT,
+            health_check: None,
+        }
+    }
+}
+
+/// Health of an upstream server, as seen from the requests sent to it and
+/// from the health checks.
+#[derive(Debug, Default)]
+struct UpstreamHealth {
+    consecutive_failures: usize,
+    /// Time until which the server is skipped after too many failures.
+    down_until: Option<Instant>,
+    /// Whether the last health check failed.
+    check_failed: bool,
+}
+
+/// Upstream server of a route.
+struct Upstream {
+    address: BackendAddress,
+    active_requests: AtomicUsize,
+    health: Mutex<UpstreamHealth>,
+}
+
+impl Upstream {
+    fn lock(&self) -> MutexGuard<'_, UpstreamHealth> {
+        self.health
+            .lock()
+            .expect("Failed to acquire the upstream health mutex")
+    }
+
+    /// Returns whether requests may be sent to the server.
+    ///
+    fn is_available(&self) -> bool {
+        let health = self.lock();
+        !health.check_failed
+            && health
+                .down_until
+                .is_none_or(|down_until| Instant::now() >= down_until)
+    }
+
+    /// Records the result of a health check. Passing a check doesn't clear
+    /// the failures of the requests sent to the server, which keep it
+    /// skipped until the failure timeout expires.
+    ///
+    fn record_check(&self, healthy: bool) {
+        let mut health = self.lock();
+
+        if healthy {
+            if health.check_failed {
+                info!("Upstream {:?} passed its health check", self.address);
+            }
+            health.check_failed = false;
+        } else if !health.check_failed {
+            warn!("Upstream {:?} failed its health check", self.address);
+            health.check_failed = true;
+        }
+    }
+}
+
+/// Use of an upstream server for a request, which counts as an active
+/// request until the lease is dropped.
+pub struct UpstreamLease {
+    upstream: Arc<Upstream>,
+    index: usize,
+    max_failures: usize,
+    failure_timeout: Duration,
+}
+
+impl UpstreamLease {
+    /// Returns the index of the upstream server, to be passed to
+    /// `UpstreamBalancer::pick` if the request has to be sent to another
+    /// server.
+    ///
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the address of the upstream server.
+    ///
+    pub fn address(&self) -> &BackendAddress {
+        &self.upstream.address
+    }
+
+    /// Records a request which the upstream server handled.
+    ///
+    pub fn record_success(&self) {
+        let mut health = self.upstream.lock();
+        health.consecutive_failures = 0;
+        health.down_until = None;
+    }
+
+    /// Records a request which failed, skipping the upstream server for a
+    /// while if it has failed too many requests in a row.
+    ///
+    pub fn record_failure(&self) {
+        let mut health = self.upstream.lock();
+        health.consecutive_failures += 1;
+
+        if health.consecutive_failures >= self.max_failures {
+            warn!(
+                "Upstream {:?} failed {} requests in a row, skipping it for {:?}",
+                self.upstream.address, health.consecutive_failures, self.failure_timeout
+            );
+            health.down_until = Some(Instant::now() + self.failure_timeout);
+        }
+    }
+}
+
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        self.upstream.active_requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Helper function which hashes a value for the hash ring.
+///
+fn hash_of<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Sends a health check request to the given upstream server, returning the
+/// status it answered with.
+///
+fn check_upstream(address: &BackendAddress, health_check: &HealthCheck) -> io::Result<StatusCode> {
+    let mut connection = BackendStream::connect(address, health_check.timeout)?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        health_check.path,
+        address.host()
+    );
+    connection.write_all(request.as_bytes())?;
+
+    Ok(read_upstream_response(BufReader::new(connection), true)?.status())
+}
+
+/// Checks the health of the given upstream servers from a separate thread,
+/// until dropped.
+struct HealthChecker {
+    cancel_sender: Option<mpsc::Sender<()>>,
+}
+
+impl HealthChecker {
+    fn new(upstreams: Vec<Arc<Upstream>>, health_check: HealthCheck) -> HealthChecker {
+        let (cancel_sender, cancel_receiver) = mpsc::channel::<()>();
+
+        thread::spawn(move || loop {
+            for upstream in &upstreams {
+                let healthy = match check_upstream(&upstream.address, &health_check) {
+                    Err(error) => {
+                        debug!("Health check of {:?} failed: {}", upstream.address, error);
+                        false
+                    }
+                    Ok(status) => status.is_success() || status.is_redirection(),
+                };
+                upstream.record_check(healthy);
+            }
+
+            if let Err(RecvTimeoutError::Disconnected) =
+                cancel_receiver.recv_timeout(health_check.interval)
+            {
+                break;
+            }
+        });
+
+        HealthChecker {
+            cancel_sender: Some(cancel_sender),
+        }
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        drop(self.cancel_sender.take());
+    }
+}
+
+/// Picks the upstream server of each request among the servers of a route,
+/// following the configured strategy and skipping the servers which are
+/// down.
+pub struct UpstreamBalancer {
+    upstreams: Vec<Arc<Upstream>>,
+    settings: BalancerSettings,
+    next_upstream: AtomicUsize,
+    /// Points of the hash ring, sorted by hash, with the index of the
+    /// upstream server they belong to.
+    hash_ring: Vec<(u64, usize)>,
+    _health_checker: Option<HealthChecker>,
+}
+
+impl UpstreamBalancer {
+    /// Creates a balancer for the given upstream servers, starting the
+    /// health checks if there are any.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function panics if there are no upstream servers.
+    pub fn new(addresses: Vec<BackendAddress>, settings: BalancerSettings) -> UpstreamBalancer {
+        assert!(!addresses.is_empty());
+
+        let upstreams = addresses
+            .into_iter()
+            .map(|address| {
+                Arc::new(Upstream {
+                    address,
+                    active_requests: AtomicUsize::new(0),
+                    health: Mutex::new(UpstreamHealth::default()),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut hash_ring = upstreams
+            .iter()
+            .enumerate()
+            .flat_map(|(index, upstream)| {
+                (0..VIRTUAL_NODES).map(move |node| (hash_of((&upstream.address, node)), index))
+            })
+            .collect::<Vec<_>>();
+        hash_ring.sort_unstable();
+
+        let health_checker = settings
+            .health_check
+            .clone()
+            .map(|health_check| HealthChecker::new(upstreams.clone(), health_check));
+
+        UpstreamBalancer {
+            upstreams,
+            settings,
+            next_upstream: AtomicUsize::new(0),
+            hash_ring,
+            _health_checker: health_checker,
+        }
+    }
+
+    /// Picks the upstream server for a request from the given client,
+    /// skipping the servers which are down or were already `tried` for this
+    /// request. Returns `None` if no server is left.
+    ///
+    pub fn pick(&self, client_ip: Option<IpAddr>, tried: &[usize]) -> Option<UpstreamLease> {
+        let count = self.upstreams.len();
+        let is_candidate =
+            |index: &usize| !tried.contains(index) && self.upstreams[*index].is_available();
+        let start = self.next_upstream.fetch_add(1, Ordering::Relaxed);
+        let in_turn = (0..count).map(|offset| (start + offset) % count);
+
+        let index = match self.settings.strategy {
+            BalancingStrategy::RoundRobin => in_turn.clone().find(is_candidate),
+            BalancingStrategy::LeastConnections => {
+                in_turn.filter(is_candidate).min_by_key(|index| {
+                    self.upstreams[*index]
+                        .active_requests
+                        .load(Ordering::SeqCst)
+                })
+            }
+            BalancingStrategy::ClientIpHash => {
+                let hash = hash_of(client_ip);
+                let ring_start = self.hash_ring.partition_point(|(point, _)| *point < hash);
+                self.hash_ring
+                    .iter()
+                    .cycle()
+                    .skip(ring_start)
+                    .take(self.hash_ring.len())
+                    .map(|(_, index)| *index)
+                    .find(is_candidate)
+            }
+        }?;
+
+        let upstream = Arc::clone(&self.upstreams[index]);
+        upstream.active_requests.fetch_add(1, Ordering::SeqCst);
+        Some(UpstreamLease {
+            upstream,
+            index,
+            max_failures: self.settings.max_failures,
+            failure_timeout: self.settings.failure_timeout,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::BufRead,
+        net::{Ipv4Addr, TcpListener},
+    };
+
+    fn sample_balancer(count: usize, settings: BalancerSettings) -> UpstreamBalancer {
+        UpstreamBalancer::new(
+            (0..count)
+                .map(|index| BackendAddress::parse(&format!("127.0.0.1:{}", 3000 + index)))
+                .collect(),
+            settings,
+        )
+    }
+
+    fn picked_port(balancer: &UpstreamBalancer, client_ip: Option<IpAddr>) -> Option<String> {
+        balancer
+            .pick(client_ip, &[])
+            .map(|lease| lease.address().host().replace("127.0.0.1:", ""))
+    }
+
+    #[test]
+    fn round_robin_skips_failing_upstreams() {
+        let balancer = sample_balancer(
+            2,
+            BalancerSettings {
+                max_failures: 2,
+                ..BalancerSettings::default()
+            },
+        );
+
+        let picked = (0..4)
+            .map(|_| picked_port(&balancer, None).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["3000", "3001", "3000", "3001"]);
+
+        let lease = balancer.pick(None, &[]).unwrap();
+        assert_eq!(lease.address().host(), "127.0.0.1:3000");
+        lease.record_failure();
+        assert_eq!(picked_port(&balancer, None).as_deref(), Some("3001"));
+        lease.record_failure();
+        assert_eq!(picked_port(&balancer, None).as_deref(), Some("3001"));
+        assert_eq!(picked_port(&balancer, None).as_deref(), Some("3001"));
+
+        balancer.pick(None, &[]).unwrap().record_failure();
+        balancer.pick(None, &[]).unwrap().record_failure();
+        assert!(balancer.pick(None, &[]).is_none());
+    }
+
+    #[test]
+    fn least_connections_picks_the_least_busy_upstream() {
+        let balancer = sample_balancer(
+            3,
+            BalancerSettings {
+                strategy: BalancingStrategy::LeastConnections,
+                ..BalancerSettings::default()
+            },
+        );
+
+        let first = balancer.pick(None, &[]).unwrap();
+        let second = balancer.pick(None, &[]).unwrap();
+        let third = balancer.pick(None, &[]).unwrap();
+        assert_ne!(first.index(), second.index());
+        assert_ne!(second.index(), third.index());
+        assert_ne!(first.index(), third.index());
+
+        let released_index = second.index();
+        drop(second);
+        for _ in 0..3 {
+            assert_eq!(balancer.pick(None, &[]).unwrap().index(), released_index);
+        }
+    }
+
+    #[test]
+    fn client_ip_hash_only_moves_clients_of_down_upstreams() {
+        let balancer = sample_balancer(
+            4,
+            BalancerSettings {
+                strategy: BalancingStrategy::ClientIpHash,
+                max_failures: 1,
+                ..BalancerSettings::default()
+            },
+        );
+        let clients = (0..50)
+            .map(|host| Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, host))))
+            .collect::<Vec<_>>();
+
+        let before = clients
+            .iter()
+            .map(|client| picked_port(&balancer, *client).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            before,
+            clients
+                .iter()
+                .map(|client| picked_port(&balancer, *client).unwrap())
+                .collect::<Vec<_>>()
+        );
+
+        balancer.pick(clients[0], &[]).unwrap().record_failure();
+        let down_port = &before[0];
+        for (client, port_before) in clients.iter().zip(&before) {
+            let port_after = picked_port(&balancer, *client).unwrap();
+            assert_ne!(&port_after, down_port);
+            if port_before != down_port {
+                assert_eq!(&port_after, port_before);
+            }
+        }
+    }
+
+    #[test]
+    fn health_checks_skip_unhealthy_upstreams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let healthy_address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                let mut connection = BufReader::new(connection.unwrap());
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    connection.read_line(&mut line).unwrap();
+                }
+                connection
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                    .unwrap();
+            }
+        });
+        let closed_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unhealthy_address = closed_listener.local_addr().unwrap().to_string();
+        drop(closed_listener);
+
+        let balancer = UpstreamBalancer::new(
+            vec![
+                BackendAddress::parse(&unhealthy_address),
+                BackendAddress::parse(&healthy_address),
+            ],
+            BalancerSettings {
+                health_check: Some(HealthCheck {
+                    path: String::from("/health"),
+                    interval: Duration::from_millis(50),
+                    timeout: Duration::from_secs(1),
+                }),
+                ..BalancerSettings::default()
+            },
+        );
+        thread::sleep(Duration::from_millis(300));
+
+        for _ in 0..4 {
+            assert_eq!(
+                balancer.pick(None, &[]).unwrap().address().host(),
+                healthy_address
+            );
+        }
+
+        let lease = balancer.pick(None, &[]).unwrap();
+        for _ in 0..DEFAULT_MAX_FAILURES {
+            lease.record_failure();
+        }
+        drop(lease);
+        thread::sleep(Duration::from_millis(200));
+        assert!(balancer.pick(None, &[]).is_none());
+    }
+}
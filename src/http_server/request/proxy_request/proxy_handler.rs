@@ -0,0 +1,486 @@
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, TcpStream},
+    time::Duration,
+};
+
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+
+use log::{debug, warn};
+
+use crate::http_server::{
+    request::{
+        backend::{is_timeout, split_path, BackendAddress, BackendStream},
+        proxy_request::{
+            proxy_balancer::{BalancerSettings, UpstreamBalancer, UpstreamLease},
+            proxy_response::read_upstream_response,
+        },
+        request::RequestHandler,
+    },
+    response::{generate_error_response, remove_hop_by_hop_headers, ResponseBody},
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Protocol the server is reached with, as reported to upstream servers.
+const FORWARDED_PROTO: &str = "http";
+
+/// Tunable options for the requests forwarded to an upstream server.
+pub struct ProxySettings {
+    /// Maximum time spent connecting to the upstream server, and waiting for
+    /// it to accept or send data.
+    pub timeout: Duration,
+    /// Whether the path prefix is removed from the forwarded request path
+    /// (so that `/prefix/users` is forwarded as `/users`).
+    pub strip_prefix: bool,
+    /// How the upstream server of each request is picked.
+    pub balancer: BalancerSettings,
+}
+
+impl Default for ProxySettings {
+    fn default() -> ProxySettings {
+        ProxySettings {
+            timeout: DEFAULT_TIMEOUT,
+            strip_prefix: false,
+            balancer: BalancerSettings::default(),
+        }
+    }
+}
+
+/// Helper function which joins the values of the given header, if any, and
+/// appends `value` to them.
+///
+fn append_to_list(headers: &HeaderMap, header_name: &str, value: &str) -> String {
+    headers
+        .get_all(header_name)
+        .iter()
+        .filter_map(|existing_value| existing_value.to_str().ok())
+        .chain(std::iter::once(value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Helper function which turns a value into a quoted string, as used in the
+/// parameters of the `Forwarded` header (section 4 of RFC 7239): quotes and
+/// backslashes are escaped with a backslash, so that the value can't end the
+/// string early and add parameters of its own.
+///
+fn quoted_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for character in value.chars() {
+        if character == '"' || character == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(character);
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Builds the headers of the request sent to the upstream server: hop-by-hop
+/// headers are removed, `Host` is set to the upstream server, and the
+/// client address, the original host and the protocol are passed along in
+/// the `X-Forwarded-For`, `X-Forwarded-Proto` and `Forwarded` headers (RFC
+/// 7239).
+///
+fn forwarded_headers(
+    request: &Request<Vec<u8>>,
+    client_ip: Option<IpAddr>,
+    upstream_host: &str,
+) -> HeaderMap {
+    let mut headers = request.headers().clone();
+    remove_hop_by_hop_headers(&mut headers);
+    // The body has already been read, so there is nothing left to expect.
+    headers.remove(header::EXPECT);
+    headers.remove(header::CONTENT_LENGTH);
+    let original_host = headers.remove(header::HOST);
+
+    let mut forwarded = Vec::new();
+    if let Some(client_ip) = client_ip {
+        let client_ip = client_ip.to_string();
+        if let Ok(value) =
+            HeaderValue::from_str(&append_to_list(&headers, "x-forwarded-for", &client_ip))
+        {
+            headers.insert("x-forwarded-for", value);
+        }
+
+        forwarded.push(if client_ip.contains(':') {
+            format!("for=\"[{client_ip}]\"")
+        } else {
+            format!("for={client_ip}")
+        });
+    }
+    if let Some(original_host) = original_host.as_ref().and_then(|host| host.to_str().ok()) {
+        forwarded.push(format!("host={}", quoted_string(original_host)));
+    }
+    forwarded.push(format!("proto={FORWARDED_PROTO}"));
+
+    if let Ok(value) =
+        HeaderValue::from_str(&append_to_list(&headers, "forwarded", &forwarded.join(";")))
+    {
+        headers.insert(header::FORWARDED, value);
+    }
+    headers.insert(
+        "x-forwarded-proto",
+        HeaderValue::from_static(FORWARDED_PROTO),
+    );
+
+    if let Ok(value) = HeaderValue::from_str(upstream_host) {
+        headers.insert(header::HOST, value);
+    }
+    if !request.body().is_empty() || request.headers().contains_key(header::CONTENT_LENGTH) {
+        headers.insert(
+            header::CONTENT_LENGTH,
+            HeaderValue::from(request.body().len()),
+        );
+    }
+    headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+
+    headers
+}
+
+/// Helper function which builds the response sent when a request couldn't
+/// be forwarded to an upstream server because of the given error.
+///
+fn gateway_error_response(error: &io::Error) -> Response<ResponseBody> {
+    generate_error_response(if is_timeout(error) {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    })
+}
+
+/// Body of an upstream response, which keeps the upstream server lease (and
+/// so counts as an active request of the server) until it has been sent.
+struct LeasedReader {
+    reader: Box<dyn Read + Send>,
+    _lease: UpstreamLease,
+}
+
+impl Read for LeasedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Request handler forwarding the requests under a given path prefix to
+/// upstream HTTP servers (acting as a reverse proxy). Each request goes to
+/// one of the upstream servers, picked by an `UpstreamBalancer`, over its
+/// own connection, and the upstream response is streamed back to the client
+/// as it is received.
+pub struct ProxyRequestHandler {
+    path: String,
+    balancer: UpstreamBalancer,
+    settings: ProxySettings,
+}
+
+impl ProxyRequestHandler {
+    /// Creates a handler forwarding requests to the given upstream servers.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function panics if there are no upstream servers.
+    pub fn new(
+        path: String,
+        upstreams: Vec<BackendAddress>,
+        settings: ProxySettings,
+    ) -> ProxyRequestHandler {
+        ProxyRequestHandler {
+            path,
+            balancer: UpstreamBalancer::new(upstreams, settings.balancer.clone()),
+            settings,
+        }
+    }
+
+    /// Sends the request over the given upstream connection and reads the
+    /// response.
+    ///
+    fn forward_request(
+        &self,
+        mut connection: BackendStream,
+        request: &Request<Vec<u8>>,
+        client_ip: Option<IpAddr>,
+        upstream: &BackendAddress,
+        path: &str,
+    ) -> io::Result<Response<ResponseBody>> {
+        let headers = forwarded_headers(request, client_ip, upstream.host());
+
+        let mut writer = BufWriter::new(&mut connection);
+        write!(writer, "{} {} HTTP/1.1\r\n", request.method(), path)?;
+        for (header_name, header_value) in &headers {
+            writer.write_all(header_name.as_str().as_bytes())?;
+            writer.write_all(b": ")?;
+            writer.write_all(header_value.as_bytes())?;
+            writer.write_all(b"\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(request.body())?;
+        writer.flush()?;
+        drop(writer);
+
+        read_upstream_response(BufReader::new(connection), request.method() == Method::HEAD)
+    }
+}
+
+impl RequestHandler<Vec<u8>> for ProxyRequestHandler {
+    /// Handles an incoming request. If the requested path is under the path
+    /// prefix, forwards the request to the upstream server and returns its
+    /// response. Otherwise returns a `None` value so that the next handler
+    /// can try to process the request.
+    ///
+    /// Upstream servers which can't be connected to are skipped, trying the
+    /// next one. A BAD GATEWAY status code is returned if none of them can
+    /// be reached or if the upstream server sends an invalid response, a
+    /// GATEWAY TIMEOUT status code if it doesn't answer in time, and a
+    /// SERVICE UNAVAILABLE status code if all upstream servers are down.
+    ///
+    fn handle_request(
+        &self,
+        stream: &TcpStream,
+        request: &Request<Vec<u8>>,
+    ) -> Option<Response<ResponseBody>> {
+        let (prefix, rest) = split_path(&self.path, request.uri().path())?;
+
+        let path = if self.settings.strip_prefix {
+            rest
+        } else {
+            request.uri().path()
+        };
+        let path = match (path, request.uri().query()) {
+            ("", None) => String::from("/"),
+            ("", Some(query)) => format!("/?{query}"),
+            (path, None) => path.to_string(),
+            (path, Some(query)) => format!("{path}?{query}"),
+        };
+
+        let client_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        let mut tried = Vec::new();
+        let mut last_error = None;
+        loop {
+            let lease = match self.balancer.pick(client_ip, &tried) {
+                None => {
+                    return Some(match last_error {
+                        None => {
+                            warn!("Proxying {} failed: all upstreams are down", prefix);
+                            generate_error_response(StatusCode::SERVICE_UNAVAILABLE)
+                        }
+                        Some(error) => gateway_error_response(&error),
+                    })
+                }
+                Some(lease) => lease,
+            };
+
+            debug!("Connecting to the upstream server at {:?}", lease.address());
+            let connection = match BackendStream::connect(lease.address(), self.settings.timeout) {
+                Err(error) => {
+                    warn!(
+                        "Connecting to {:?} for {} failed: {}",
+                        lease.address(),
+                        prefix,
+                        error
+                    );
+                    lease.record_failure();
+                    tried.push(lease.index());
+                    last_error = Some(error);
+                    continue;
+                }
+                Ok(connection) => connection,
+            };
+
+            return match self.forward_request(
+                connection,
+                request,
+                client_ip,
+                lease.address(),
+                &path,
+            ) {
+                Err(error) => {
+                    warn!(
+                        "Proxying {} to {:?} failed: {}",
+                        prefix,
+                        lease.address(),
+                        error
+                    );
+                    lease.record_failure();
+                    Some(gateway_error_response(&error))
+                }
+                Ok(response) => {
+                    lease.record_success();
+                    Some(response.map(|body| match body {
+                        ResponseBody::Stream { reader, length } => ResponseBody::from_reader(
+                            LeasedReader {
+                                reader,
+                                _lease: lease,
+                            },
+                            length,
+                        ),
+                        body => body,
+                    }))
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{BufRead, Read},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn forwarded_host_is_escaped() {
+        let request = Request::builder()
+            .header(header::HOST, "a\";for=203.0.113.7;by=\"\\")
+            .body(Vec::new())
+            .unwrap();
+
+        let headers = forwarded_headers(&request, None, "127.0.0.1:3000");
+
+        assert_eq!(
+            headers[header::FORWARDED],
+            "host=\"a\\\";for=203.0.113.7;by=\\\"\\\\\";proto=http"
+        );
+    }
+
+    #[test]
+    fn request_is_forwarded_with_rewritten_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let upstream = thread::spawn(move || {
+            let mut connection = BufReader::new(listener.accept().unwrap().0);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                connection.read_line(&mut head).unwrap();
+            }
+            let mut body = [0; 4];
+            connection.read_exact(&mut body).unwrap();
+
+            connection
+                .get_mut()
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\nKeep-Alive: 5\r\n\r\nok")
+                .unwrap();
+            (head, body)
+        });
+
+        let handler = ProxyRequestHandler::new(
+            String::from("api"),
+            vec![BackendAddress::parse(&address)],
+            ProxySettings {
+                strip_prefix: true,
+                ..ProxySettings::default()
+            },
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/users?page=2")
+            .header("host", "example.com:8080")
+            .header("x-forwarded-for", "203.0.113.7")
+            .header("connection", "keep-alive, x-secret")
+            .header("x-secret", "1")
+            .header("content-length", "4")
+            .body(b"name".to_vec())
+            .unwrap();
+        let client_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client_stream = TcpStream::connect(client_listener.local_addr().unwrap()).unwrap();
+
+        let response = handler.handle_request(&client_stream, &request).unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(response.headers().get("keep-alive").is_none());
+        assert_eq!(response.body().len(), Some(2));
+
+        let (head, body) = upstream.join().unwrap();
+        let head = head.to_ascii_lowercase();
+        assert!(head.starts_with("post /users?page=2 http/1.1\r\n"));
+        assert!(head.contains(&format!("host: {address}\r\n")));
+        assert!(head.contains("x-forwarded-for: 203.0.113.7, 127.0.0.1\r\n"));
+        assert!(head.contains("forwarded: for=127.0.0.1;host=\"example.com:8080\";proto=http\r\n"));
+        assert!(head.contains("x-forwarded-proto: http\r\n"));
+        assert!(head.contains("connection: close\r\n"));
+        assert!(!head.contains("x-secret"));
+        assert_eq!(&body, b"name");
+
+        let request = Request::builder().uri("/apiary").body(Vec::new()).unwrap();
+        assert!(handler.handle_request(&client_stream, &request).is_none());
+    }
+
+    fn closed_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn unreachable_upstream_is_a_bad_gateway_then_unavailable() {
+        let handler = ProxyRequestHandler::new(
+            String::from(""),
+            vec![BackendAddress::parse(&closed_address())],
+            ProxySettings {
+                balancer: BalancerSettings {
+                    max_failures: 1,
+                    ..BalancerSettings::default()
+                },
+                ..ProxySettings::default()
+            },
+        );
+        let request = Request::builder().uri("/").body(Vec::new()).unwrap();
+        let client_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client_stream = TcpStream::connect(client_listener.local_addr().unwrap()).unwrap();
+        let status = || {
+            handler
+                .handle_request(&client_stream, &request)
+                .unwrap()
+                .status()
+        };
+
+        assert_eq!(status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn unreachable_upstream_is_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                let mut connection = BufReader::new(connection.unwrap());
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    connection.read_line(&mut line).unwrap();
+                }
+                connection
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+            }
+        });
+
+        let handler = ProxyRequestHandler::new(
+            String::from(""),
+            vec![
+                BackendAddress::parse(&closed_address()),
+                BackendAddress::parse(&address),
+            ],
+            ProxySettings::default(),
+        );
+        let request = Request::builder().uri("/").body(Vec::new()).unwrap();
+        let client_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client_stream = TcpStream::connect(client_listener.local_addr().unwrap()).unwrap();
+
+        for _ in 0..6 {
+            assert_eq!(
+                handler
+                    .handle_request(&client_stream, &request)
+                    .unwrap()
+                    .status(),
+                StatusCode::OK
+            );
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::io::{self, BufRead, Read};
+
+use http::{header, HeaderName, HeaderValue, Response, StatusCode};
+
+use log::debug;
+
+use crate::http_server::response::{remove_hop_by_hop_headers, ResponseBody};
+
+/// Maximum size of the status line and headers of an upstream response.
+const MAX_HEADERS_SIZE: u64 = 64 * 1024; // 64KB
+
+/// Helper function which builds the error returned for malformed upstream
+/// responses.
+///
+fn invalid_response(details: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid upstream response: {details}"),
+    )
+}
+
+/// Reads a single line, without its line ending (CRLF or bare LF). Lines
+/// longer than `max_length` are rejected.
+///
+fn read_line<R: BufRead>(reader: &mut R, max_length: u64) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.take(max_length).read_until(b'\n', &mut line)?;
+    if line.pop() != Some(b'\n') {
+        return Err(invalid_response("line is too long or truncated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line).map_err(|_| invalid_response("line isn't valid UTF-8"))
+}
+
+/// Reader decoding a body sent with the chunked transfer coding, as it is
+/// received. Chunk extensions and trailer fields are ignored.
+struct ChunkedReader<R: BufRead> {
+    inner: R,
+    /// Number of bytes left in the current chunk.
+    remaining: u64,
+    finished: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            finished: false,
+        }
+    }
+
+    /// Reads the size line of the next chunk. Once the last chunk is
+    /// reached, the trailer section is skipped.
+    ///
+    fn start_chunk(&mut self) -> io::Result<()> {
+        let size_line = read_line(&mut self.inner, MAX_HEADERS_SIZE)?;
+        let size = size_line.split(';').next().unwrap_or("").trim();
+        self.remaining = u64::from_str_radix(size, 16)
+            .map_err(|_| invalid_response(&format!("invalid chunk size {size_line:?}")))?;
+
+        if self.remaining == 0 {
+            while !read_line(&mut self.inner, MAX_HEADERS_SIZE)?.is_empty() {}
+            self.finished = true;
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.finished {
+            self.start_chunk()?;
+        }
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let max_length = buf.len().min(self.remaining as usize);
+        let read_bytes = self.inner.read(&mut buf[..max_length])?;
+        if read_bytes == 0 {
+            return Err(invalid_response("body ended in the middle of a chunk"));
+        }
+
+        self.remaining -= read_bytes as u64;
+        if self.remaining == 0 && !read_line(&mut self.inner, MAX_HEADERS_SIZE)?.is_empty() {
+            return Err(invalid_response("chunk is longer than its declared size"));
+        }
+
+        Ok(read_bytes)
+    }
+}
+
+/// Reads the status line and headers of an upstream response. Interim
+/// (1xx) responses are skipped.
+///
+fn read_response_head<R: BufRead>(upstream: &mut R) -> io::Result<Response<()>> {
+    loop {
+        let mut upstream = upstream.take(MAX_HEADERS_SIZE);
+
+        let status_line = read_line(&mut upstream, MAX_HEADERS_SIZE)?;
+        let status = match status_line.split_whitespace().collect::<Vec<_>>()[..] {
+            [version, status, ..] if version.starts_with("HTTP/") => {
+                StatusCode::from_bytes(status.as_bytes())
+                    .map_err(|_| invalid_response(&format!("invalid status {status:?}")))?
+            }
+            _ => {
+                return Err(invalid_response(&format!(
+                    "invalid status line {status_line:?}"
+                )))
+            }
+        };
+
+        let mut response = Response::new(());
+        *response.status_mut() = status;
+        loop {
+            let header_line = read_line(&mut upstream, MAX_HEADERS_SIZE)?;
+            if header_line.is_empty() {
+                break;
+            }
+
+            let parsed_header = header_line.split_once(':').and_then(|(before, after)| {
+                let name = HeaderName::from_bytes(before.trim().as_bytes()).ok()?;
+                let value = HeaderValue::from_str(after.trim()).ok()?;
+                Some((name, value))
+            });
+            match parsed_header {
+                None => debug!("Couldn't parse upstream header: {:?}", header_line),
+                Some((name, value)) => {
+                    response.headers_mut().append(name, value);
+                }
+            }
+        }
+
+        if status.is_informational() && status != StatusCode::SWITCHING_PROTOCOLS {
+            debug!("Skipping interim upstream response {}", status);
+            continue;
+        }
+
+        return Ok(response);
+    }
+}
+
+/// Reads the response sent by an upstream server. Only the status line and
+/// headers are read here: the returned body streams the rest of the
+/// response as it is received, decoding the chunked transfer coding if
+/// needed, so that it can be sent to the client right away. Hop-by-hop
+/// headers are removed, since the connection handler sets its own.
+///
+/// `is_head` tells whether the request was a HEAD request, whose response
+/// doesn't have a body.
+///
+pub fn read_upstream_response<R: BufRead + Send + 'static>(
+    mut upstream: R,
+    is_head: bool,
+) -> io::Result<Response<ResponseBody>> {
+    let (parts, ()) = read_response_head(&mut upstream)?.into_parts();
+
+    let is_chunked = parts
+        .headers
+        .get(header::TRANSFER_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|codings| codings.rsplit(',').next())
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+    let content_length = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let has_body = !is_head
+        && !parts.status.is_informational()
+        && parts.status != StatusCode::NO_CONTENT
+        && parts.status != StatusCode::NOT_MODIFIED;
+
+    let body = match (has_body, is_chunked, content_length) {
+        (false, _, _) => ResponseBody::empty(),
+        (true, true, _) => ResponseBody::from_reader(ChunkedReader::new(upstream), None),
+        (true, false, Some(content_length)) => {
+            ResponseBody::from_reader(upstream.take(content_length), Some(content_length))
+        }
+        (true, false, None) => ResponseBody::from_reader(upstream, None),
+    };
+
+    let mut response = Response::from_parts(parts, body);
+    remove_hop_by_hop_headers(response.headers_mut());
+    if is_chunked {
+        response.headers_mut().remove(header::CONTENT_LENGTH);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn read_body(response: Response<ResponseBody>) -> io::Result<String> {
+        let mut body = Vec::new();
+        match response.into_body() {
+            ResponseBody::Stream { mut reader, .. } => reader.read_to_end(&mut body)?,
+            ResponseBody::Bytes(bytes) => bytes.len(),
+            ResponseBody::Raw(_) => panic!("Upstream responses should not be raw"),
+        };
+
+        Ok(String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn chunked_response_is_decoded() {
+        let upstream = "HTTP/1.1 100 Continue\r\n\r\n\
+                        HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\
+                        Connection: close, X-Internal\r\nX-Internal: 1\r\n\r\n\
+                        5;ext=1\r\nHello\r\n7\r\n, World\r\n0\r\nExpires: 0\r\n\r\n";
+        let response = read_upstream_response(Cursor::new(upstream), false).unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().is_empty());
+        assert_eq!(read_body(response).unwrap(), "Hello, World");
+    }
+
+    #[test]
+    fn body_is_delimited_by_content_length() {
+        let upstream = "HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nHello, World";
+        let response = read_upstream_response(Cursor::new(upstream), false).unwrap();
+
+        assert_eq!(response.body().len(), Some(5));
+        assert_eq!(read_body(response).unwrap(), "Hello");
+
+        let head_response = read_upstream_response(Cursor::new(upstream), true).unwrap();
+        assert!(head_response.body().is_empty());
+        assert_eq!(head_response.headers()[header::CONTENT_LENGTH], "5");
+    }
+
+    #[test]
+    fn truncated_chunk_is_an_error() {
+        let upstream = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nA\r\nHello";
+        let response = read_upstream_response(Cursor::new(upstream), false).unwrap();
+
+        assert!(read_body(response).is_err());
+    }
+}
@@ -1,106 +1,707 @@
-use std::{io::prelude::*, net::TcpStream};
+use std::fmt;
+use std::io::{prelude::*, ErrorKind};
+use std::net::TcpStream;
 
-use http::{Request, Response, StatusCode, Version};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, Version};
 
 use log::debug;
 
+use crate::http_server::response::ResponseBody;
+
 const BUFFER_SIZE: usize = 8 * 1024; // 8KB
 
+/// Fields which aren't accepted in the trailer section of a chunked request
+/// (section 6.5.1 of RFC 9110): since trailers are merged into the request
+/// headers, they could otherwise change how the request is framed, routed
+/// or authenticated, or how its content is interpreted.
+const FORBIDDEN_TRAILER_FIELDS: [HeaderName; 16] = [
+    header::AUTHORIZATION,
+    header::CACHE_CONTROL,
+    header::CONNECTION,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::CONTENT_TYPE,
+    header::COOKIE,
+    header::EXPECT,
+    header::HOST,
+    header::MAX_FORWARDS,
+    header::PROXY_AUTHORIZATION,
+    header::RANGE,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+];
+
 pub trait RequestHandler<T> {
     /// The `handle_request` trait method should return None if the
     /// corresponding handler shouldn't handle the supplied request. Otherwise
     /// it should return the correct response.
     ///
-    fn handle_request(&self, stream: &TcpStream, request: &Request<T>) -> Option<Response<T>>;
+    fn handle_request(
+        &self,
+        stream: &TcpStream,
+        request: &Request<T>,
+    ) -> Option<Response<ResponseBody>>;
+}
+
+/// Identifier assigned to each request by the connection handler. It is
+/// stored in the request extensions, so that handlers can tag their logs
+/// with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequestId(pub u64);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-/// The `handle_request` function reads in an HTTP request from the given TCP
-/// stream and returns it. If a valid request can't be read, the HTTP status
-/// to be sent back is returned, wrapped into an `Err` instance. Input from the
-/// TCP stream is expected to be UTF-8 encoded data. If this isn't the case,
-/// a BAD REQUEST status code is returned.
+/// Returns the position right after the blank line which ends the metadata
+/// section of a request (or of a CGI response), if it has already been
+/// received. Both CRLF and bare LF line endings are accepted.
 ///
-pub fn load_request(mut stream: &TcpStream) -> Result<Request<String>, StatusCode> {
-    let mut buffer = [0; BUFFER_SIZE + 1];
-    if let Err(_) = stream.read(&mut buffer) {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    let request_string = if let Ok(text) = String::from_utf8(buffer.to_vec()) {
-        text.trim_end_matches(char::from(0)).to_owned()
-    } else {
-        debug!("Error reading UTF-8 from request buffer");
-        return Err(StatusCode::BAD_REQUEST);
-    };
-
-    if request_string.len() > BUFFER_SIZE {
-        return Err(StatusCode::PAYLOAD_TOO_LARGE);
-    }
-
-    let mut lines_iter = request_string.lines();
-    let start_line = if let Some(line_result) = lines_iter.next() {
-        line_result
-    } else {
-        debug!("No data received");
-        return Err(StatusCode::BAD_REQUEST);
-    };
-
-    let split_line: Vec<&str> = start_line.split_whitespace().collect();
-    if split_line.len() != 3 {
-        debug!("Invalid start line_result length");
-        return Err(StatusCode::BAD_REQUEST);
-    };
-
-    let mut request = Request::builder().method(split_line[0]).uri(split_line[1]);
-
-    let http_version = match split_line[2] {
-        "HTTP/0.9" => Version::HTTP_09,
-        "HTTP/1.0" => Version::HTTP_10,
-        "HTTP/1.1" => Version::HTTP_11,
-        "HTTP/2" => Version::HTTP_2,
-        "HTTP/3" => Version::HTTP_3,
-        _ => return Err(StatusCode::BAD_REQUEST),
-    };
-
-    request = request.version(http_version);
-
-    loop {
-        let next_line = if let Some(line_result) = lines_iter.next() {
-            line_result
-        } else {
-            return match request.body(String::from("")) {
+pub fn find_metadata_end(data: &[u8]) -> Option<usize> {
+    let crlf_end = data
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4);
+    let lf_end = data
+        .windows(2)
+        .position(|window| window == b"\n\n")
+        .map(|position| position + 2);
+
+    match (crlf_end, lf_end) {
+        (Some(crlf_end), Some(lf_end)) => Some(crlf_end.min(lf_end)),
+        (crlf_end, lf_end) => crlf_end.or(lf_end),
+    }
+}
+
+/// Maps an error which happened while reading a request into the HTTP status
+/// to be sent back. Read timeouts (set up for idle connections) become a
+/// REQUEST TIMEOUT status code.
+///
+fn read_error_status(error: &std::io::Error) -> StatusCode {
+    match error.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => StatusCode::REQUEST_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Reads consecutive HTTP requests from a stream. Data received past the end
+/// of a request is kept in a per-connection buffer and used as the start of
+/// the next one, so that requests pipelined by the client are not lost.
+///
+pub struct RequestReader<R> {
+    stream: R,
+    pending: Vec<u8>,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(stream: R) -> RequestReader<R> {
+        RequestReader {
+            stream,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Reads another piece of data from the stream into the pending buffer.
+    /// Returns the number of bytes read, which is zero once the stream ends.
+    ///
+    fn fill_buffer(&mut self) -> Result<usize, std::io::Error> {
+        let mut buffer = [0; BUFFER_SIZE];
+        let read_bytes = self.stream.read(&mut buffer)?;
+        self.pending.extend_from_slice(&buffer[..read_bytes]);
+
+        Ok(read_bytes)
+    }
+
+    /// Reads more data into the pending buffer. Running out of data at this
+    /// point means the request was cut short.
+    ///
+    fn read_more(&mut self) -> Result<(), StatusCode> {
+        match self.fill_buffer() {
+            Err(error) => Err(read_error_status(&error)),
+            Ok(0) => {
+                debug!("Stream ended before the whole body was received");
+                Err(StatusCode::BAD_REQUEST)
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Reads from the stream until the whole metadata section (start line and
+    /// headers) of the next request has been received, and takes it out of
+    /// the pending buffer. Returns `None` if the stream was closed or timed
+    /// out before any data of a new request arrived.
+    ///
+    fn read_metadata(&mut self) -> Result<Option<Vec<u8>>, StatusCode> {
+        loop {
+            if let Some(metadata_end) = find_metadata_end(&self.pending) {
+                if metadata_end > BUFFER_SIZE {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                return Ok(Some(self.pending.drain(..metadata_end).collect()));
+            }
+
+            if self.pending.len() > BUFFER_SIZE {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+
+            let is_idle = self.pending.is_empty();
+            match self.fill_buffer() {
+                Err(error)
+                    if is_idle && read_error_status(&error) == StatusCode::REQUEST_TIMEOUT =>
+                {
+                    return Ok(None)
+                }
+                Err(error) => return Err(read_error_status(&error)),
+                Ok(0) if is_idle => return Ok(None),
+                Ok(0) => {
+                    debug!("Stream ended before the end of the metadata section");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Reads a request body of the given length, taking it out of the
+    /// pending buffer.
+    ///
+    fn read_body(&mut self, content_length: usize) -> Result<Vec<u8>, StatusCode> {
+        while self.pending.len() < content_length {
+            self.read_more()?;
+        }
+
+        Ok(self.pending.drain(..content_length).collect())
+    }
+
+    /// Takes a single line out of the pending buffer, reading more from the
+    /// stream if needed. The line ending (CRLF or bare LF) is not included.
+    ///
+    fn take_line(&mut self) -> Result<String, StatusCode> {
+        loop {
+            if let Some(line_end) = self.pending.iter().position(|byte| *byte == b'\n') {
+                let mut line: Vec<u8> = self.pending.drain(..=line_end).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+
+                return String::from_utf8(line).map_err(|_| {
+                    debug!("Invalid UTF-8 in chunked body metadata");
+                    StatusCode::BAD_REQUEST
+                });
+            }
+
+            if self.pending.len() > BUFFER_SIZE {
+                debug!("Chunked body metadata line is too long");
+                return Err(StatusCode::BAD_REQUEST);
+            }
+
+            self.read_more()?;
+        }
+    }
+
+    /// Decodes a body sent with the chunked transfer coding. Chunk extensions
+    /// are ignored. Returns the decoded body along with the trailer fields
+    /// sent after the last chunk. As with the metadata section, the trailer
+    /// section can't be larger than the read buffer: a REQUEST HEADER FIELDS
+    /// TOO LARGE status code is returned otherwise.
+    ///
+    fn read_chunked_body(
+        &mut self,
+        max_body_size: usize,
+    ) -> Result<(Vec<u8>, HeaderMap), StatusCode> {
+        let mut body = Vec::new();
+
+        loop {
+            let size_line = self.take_line()?;
+            let size = size_line.split(';').next().unwrap_or("").trim();
+            let size = match usize::from_str_radix(size, 16) {
                 Err(_) => {
-                    debug!("Request ended at metadata section");
-                    Err(StatusCode::BAD_REQUEST)
+                    debug!("Invalid chunk size: {:?}", size_line);
+                    return Err(StatusCode::BAD_REQUEST);
                 }
-                Ok(request) => Ok(request),
+                Ok(size) => size,
             };
+
+            if size == 0 {
+                break;
+            }
+
+            if size > max_body_size - body.len() {
+                debug!("Request body exceeds the maximum size");
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+
+            body.extend(self.read_body(size)?);
+
+            if !self.take_line()?.is_empty() {
+                debug!("Chunk data is longer than its declared size");
+                return Err(StatusCode::BAD_REQUEST);
+            }
+        }
+
+        let mut trailers = HeaderMap::new();
+        let mut trailers_size = 0;
+        loop {
+            let trailer_line = self.take_line()?;
+            if trailer_line.is_empty() {
+                break;
+            }
+
+            trailers_size += trailer_line.len();
+            if trailers_size > BUFFER_SIZE {
+                debug!("Trailer section is too large");
+                return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            }
+
+            let parsed_trailer = trailer_line.split_once(':').and_then(|(before, after)| {
+                let name = HeaderName::from_bytes(before.trim().as_bytes()).ok()?;
+                let value = HeaderValue::from_str(after.trim()).ok()?;
+                Some((name, value))
+            });
+
+            match parsed_trailer {
+                None => {
+                    debug!("Invalid trailer field: {:?}", trailer_line);
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                Some((name, value)) => trailers.append(name, value),
+            };
+        }
+
+        Ok((body, trailers))
+    }
+
+    /// The `load_request` method reads in the next HTTP request from the
+    /// stream and returns it. `None` is returned if the stream is closed (or
+    /// its read timeout expires) before a new request starts, which is how
+    /// idle persistent connections end. If a valid request can't be read, the
+    /// HTTP status to be sent back is returned, wrapped into an `Err`
+    /// instance. The metadata section is expected to be UTF-8 encoded data.
+    /// If this isn't the case, a BAD REQUEST status code is returned. The body
+    /// is kept as raw bytes.
+    ///
+    /// The metadata section is read up to the blank line which ends it, and
+    /// then exactly `Content-Length` bytes are read as the request body (a
+    /// repeated or invalid `Content-Length` is a BAD REQUEST). Bodies sent
+    /// with `Transfer-Encoding: chunked` are decoded instead, and the request
+    /// is handed over with a `Content-Length` header matching the decoded body
+    /// and with any trailer fields merged into its headers. Other transfer
+    /// codings (including `chunked` combined with another coding) aren't
+    /// supported, and result in a NOT IMPLEMENTED status code.
+    /// Requests whose body is larger than `max_body_size` are rejected with a
+    /// PAYLOAD TOO LARGE status code. Any data following the request is kept
+    /// for the next call.
+    ///
+    pub fn load_request(
+        &mut self,
+        max_body_size: usize,
+    ) -> Result<Option<Request<Vec<u8>>>, StatusCode> {
+        let metadata = match self.read_metadata()? {
+            None => return Ok(None),
+            Some(metadata) => metadata,
+        };
+
+        let request_string = if let Ok(text) = std::str::from_utf8(&metadata) {
+            text
+        } else {
+            debug!("Error reading UTF-8 from request buffer");
+            return Err(StatusCode::BAD_REQUEST);
         };
 
-        if next_line.is_empty() {
-            break;
+        let mut lines_iter = request_string.lines();
+        let start_line = if let Some(line_result) = lines_iter.next() {
+            line_result
+        } else {
+            debug!("No data received");
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        let split_line: Vec<&str> = start_line.split_whitespace().collect();
+        if split_line.len() != 3 {
+            debug!("Invalid start line_result length");
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        let mut request = Request::builder().method(split_line[0]).uri(split_line[1]);
+
+        let http_version = match split_line[2] {
+            "HTTP/0.9" => Version::HTTP_09,
+            "HTTP/1.0" => Version::HTTP_10,
+            "HTTP/1.1" => Version::HTTP_11,
+            "HTTP/2" => Version::HTTP_2,
+            "HTTP/3" => Version::HTTP_3,
+            _ => return Err(StatusCode::BAD_REQUEST),
+        };
+
+        request = request.version(http_version);
+
+        for next_line in lines_iter {
+            if next_line.is_empty() {
+                break;
+            }
+
+            let split_line = next_line.split_once(':');
+            request = match split_line {
+                None => {
+                    debug!("Invalid header line_result format");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                Some((before, after)) => request.header(before.trim(), after.trim()),
+            }
         }
 
-        let split_line = next_line.split_once(":");
-        request = match split_line {
-            None => {
-                debug!("Invalid header line_result format");
+        let mut request = match request.body(Vec::new()) {
+            Err(_) => {
+                debug!("Malformed request");
                 return Err(StatusCode::BAD_REQUEST);
             }
-            Some((before, after)) => request.header(before, after),
+            Ok(request) => request,
+        };
+
+        if request.headers().contains_key(header::TRANSFER_ENCODING) {
+            let transfer_codings = request
+                .headers()
+                .get_all(header::TRANSFER_ENCODING)
+                .iter()
+                .collect::<Vec<_>>();
+            let is_chunked = match transfer_codings[..] {
+                [coding] => coding
+                    .to_str()
+                    .is_ok_and(|coding| coding.trim().eq_ignore_ascii_case("chunked")),
+                _ => false,
+            };
+            if !is_chunked {
+                debug!("Unsupported transfer codings: {:?}", transfer_codings);
+                return Err(StatusCode::NOT_IMPLEMENTED);
+            }
+
+            let (body, trailers) = self.read_chunked_body(max_body_size)?;
+
+            let headers = request.headers_mut();
+            headers.remove(header::TRANSFER_ENCODING);
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+            for (name, value) in trailers {
+                match name {
+                    None => (),
+                    Some(name) if FORBIDDEN_TRAILER_FIELDS.contains(&name) => {
+                        debug!("Ignoring forbidden trailer field: {:?}", name);
+                    }
+                    Some(name) => {
+                        headers.append(name, value);
+                    }
+                }
+            }
+
+            *request.body_mut() = body;
+            return Ok(Some(request));
+        }
+
+        if request
+            .headers()
+            .get_all(header::CONTENT_LENGTH)
+            .iter()
+            .count()
+            > 1
+        {
+            debug!("Repeated Content-Length header");
+            return Err(StatusCode::BAD_REQUEST);
         }
+
+        let content_length = match request.headers().get(header::CONTENT_LENGTH) {
+            None => 0,
+            Some(value) => match value.to_str().ok().and_then(|v| v.parse::<usize>().ok()) {
+                None => {
+                    debug!("Invalid Content-Length header");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                Some(content_length) => content_length,
+            },
+        };
+
+        if content_length > max_body_size {
+            debug!("Request body exceeds the maximum size");
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        *request.body_mut() = self.read_body(content_length)?;
+        Ok(Some(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader which hands out its data in fixed-size pieces, simulating a
+    /// request split across several TCP segments.
+    struct SegmentedReader {
+        data: Vec<u8>,
+        position: usize,
+        segment_size: usize,
+    }
+
+    impl Read for SegmentedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let end = (self.position + self.segment_size)
+                .min(self.data.len())
+                .min(self.position + buf.len());
+            let read_bytes = end - self.position;
+            buf[..read_bytes].copy_from_slice(&self.data[self.position..end]);
+            self.position = end;
+            Ok(read_bytes)
+        }
+    }
+
+    fn segmented(data: &str, segment_size: usize) -> RequestReader<SegmentedReader> {
+        RequestReader::new(SegmentedReader {
+            data: data.as_bytes().to_vec(),
+            position: 0,
+            segment_size,
+        })
+    }
+
+    #[test]
+    fn body_split_across_segments_is_fully_read() {
+        let mut stream = segmented(
+            "POST /cgi-bin/simple_form.py HTTP/1.1\r\n\
+            Content-Length: 30\r\n\
+            \r\n\
+            first_name=Jane&last_name=Doe!",
+            7,
+        );
+
+        let request = stream.load_request(1024).unwrap().unwrap();
+
+        assert_eq!(request.body(), b"first_name=Jane&last_name=Doe!");
+    }
+
+    #[test]
+    fn multi_line_body_is_passed_through_unchanged() {
+        let body = "comment=first line\nsecond line\n\nfourth line\n";
+        let mut stream = segmented(
+            &format!(
+                "POST /cgi-bin/bash_document.sh HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+            5,
+        );
+
+        let request = stream.load_request(1024).unwrap().unwrap();
+
+        assert_eq!(request.body(), body.as_bytes());
+    }
+
+    #[test]
+    fn crlf_body_is_passed_through_unchanged() {
+        let body = "{\r\n  \"name\": \"value\"\r\n}\r\n\r\n";
+        let mut stream = segmented(
+            &format!(
+                "POST /cgi-bin/bash_document.sh HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+            64,
+        );
+
+        let request = stream.load_request(1024).unwrap().unwrap();
+
+        assert_eq!(request.body(), body.as_bytes());
+        assert_eq!(request.body().len(), body.len());
+    }
+
+    #[test]
+    fn body_larger_than_maximum_is_rejected() {
+        let mut stream = segmented(
+            "POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nHello World",
+            64,
+        );
+
+        assert_eq!(
+            stream.load_request(10).unwrap_err(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn binary_body_is_kept_intact() {
+        let mut data = b"POST /upload HTTP/1.1\r\nContent-Length: 6\r\n\r\n".to_vec();
+        data.extend_from_slice(&[0x89, 0x50, 0x00, 0xff, 0x0d, 0x0a]);
+        let mut stream = RequestReader::new(SegmentedReader {
+            data,
+            position: 0,
+            segment_size: 64,
+        });
+
+        let request = stream.load_request(1024).unwrap().unwrap();
+
+        assert_eq!(request.body(), &[0x89, 0x50, 0x00, 0xff, 0x0d, 0x0a]);
     }
 
-    let mut body = String::from("");
-    while let Some(line_entry) = lines_iter.next() {
-        body.push_str(&line_entry);
+    #[test]
+    fn truncated_body_is_rejected() {
+        let mut stream = segmented("POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\nHello", 64);
+
+        assert_eq!(
+            stream.load_request(1024).unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
     }
 
-    match request.body(body) {
-        Err(_) => {
-            debug!("Malformed request");
-            Err(StatusCode::BAD_REQUEST)
+    #[test]
+    fn chunked_body_is_decoded() {
+        let mut stream = segmented(
+            "POST /cgi-bin/bash_document.sh HTTP/1.1\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5;name=value\r\nHello\r\n\
+            7\r\n, World\r\n\
+            0\r\n\
+            X-Checksum: abc\r\n\
+            \r\n",
+            3,
+        );
+
+        let request = stream.load_request(1024).unwrap().unwrap();
+
+        assert_eq!(request.body(), b"Hello, World");
+        assert_eq!(request.headers()[header::CONTENT_LENGTH], "12");
+        assert_eq!(request.headers()["x-checksum"], "abc");
+        assert!(request.headers().get(header::TRANSFER_ENCODING).is_none());
+    }
+
+    #[test]
+    fn forbidden_trailer_fields_are_dropped() {
+        let mut stream = segmented(
+            "POST / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5\r\nHello\r\n\
+            0\r\n\
+            Content-Length: 100\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Host: attacker.example\r\n\
+            Authorization: Basic YTpi\r\n\
+            Content-Type: text/html\r\n\
+            X-Checksum: abc\r\n\
+            \r\n",
+            64,
+        );
+
+        let request = stream.load_request(1024).unwrap().unwrap();
+        let headers = request.headers();
+
+        assert_eq!(
+            headers
+                .get_all(header::CONTENT_LENGTH)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["5"]
+        );
+        assert_eq!(
+            headers.get_all(header::HOST).iter().collect::<Vec<_>>(),
+            ["example.com"]
+        );
+        assert!(headers.get(header::TRANSFER_ENCODING).is_none());
+        assert!(headers.get(header::AUTHORIZATION).is_none());
+        assert!(headers.get(header::CONTENT_TYPE).is_none());
+        assert_eq!(headers["x-checksum"], "abc");
+    }
+
+    #[test]
+    fn oversized_trailer_section_is_rejected() {
+        let trailers = "X-Padding: abcdefghijklmnopqrstuvwxyz\r\n".repeat(BUFFER_SIZE / 32);
+        let mut stream = segmented(
+            &format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n{trailers}\r\n"),
+            1024,
+        );
+
+        assert_eq!(
+            stream.load_request(1024).unwrap_err(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn transfer_codings_other_than_chunked_are_rejected() {
+        for transfer_encoding in [
+            "Transfer-Encoding: gzip, chunked\r\n",
+            "Transfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n",
+            "Transfer-Encoding: gzip\r\n",
+        ] {
+            let mut stream = segmented(
+                &format!("POST / HTTP/1.1\r\n{transfer_encoding}\r\n0\r\n\r\n"),
+                64,
+            );
+
+            assert_eq!(
+                stream.load_request(1024).unwrap_err(),
+                StatusCode::NOT_IMPLEMENTED
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_content_length_is_rejected() {
+        for content_length in [
+            "Content-Length: 5\r\nContent-Length: 5\r\n",
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "Content-Length: 5, 6\r\n",
+        ] {
+            let mut stream = segmented(
+                &format!("POST / HTTP/1.1\r\n{content_length}\r\nHello!"),
+                64,
+            );
+
+            assert_eq!(
+                stream.load_request(1024).unwrap_err(),
+                StatusCode::BAD_REQUEST
+            );
         }
-        Ok(request) => Ok(request),
+    }
+
+    #[test]
+    fn malformed_chunk_is_rejected() {
+        let mut stream = segmented(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nHello\r\n0\r\n\r\n",
+            64,
+        );
+
+        assert_eq!(
+            stream.load_request(1024).unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn closed_stream_yields_no_request() {
+        let mut stream = segmented("", 64);
+
+        assert!(matches!(stream.load_request(1024), Ok(None)));
+    }
+
+    #[test]
+    fn pipelined_requests_are_read_in_order() {
+        let mut stream = segmented(
+            "POST /first HTTP/1.1\r\nContent-Length: 5\r\n\r\nHello\
+            GET /second HTTP/1.1\r\n\r\n\
+            GET /third HTTP/1.1\r\n\r\n",
+            1024,
+        );
+
+        let first = stream.load_request(1024).unwrap().unwrap();
+        let second = stream.load_request(1024).unwrap().unwrap();
+        let third = stream.load_request(1024).unwrap().unwrap();
+
+        assert_eq!(first.uri(), "/first");
+        assert_eq!(first.body(), b"Hello");
+        assert_eq!(second.uri(), "/second");
+        assert!(second.body().is_empty());
+        assert_eq!(third.uri(), "/third");
+        assert!(matches!(stream.load_request(1024), Ok(None)));
     }
 }
@@ -0,0 +1 @@
+pub mod scgi_handler;
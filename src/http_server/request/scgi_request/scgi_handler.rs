@@ -0,0 +1,324 @@
+use std::{
+    io::{self, BufWriter, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use http::{HeaderName, Request, Response};
+
+use log::{debug, warn};
+
+use crate::http_server::{
+    request::{
+        backend::{is_timeout, split_path, BackendAddress, BackendStream},
+        cgi_request::{
+            cgi_error::CgiError,
+            cgi_handler::{default_excluded_headers, generate_environment_variables},
+            cgi_metavariables::{CGIMetavariable, CGIMetavariableMap},
+            cgi_response::{convert_cgi_response_to_http, read_cgi_response},
+        },
+        request::RequestHandler,
+    },
+    response::ResponseBody,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tunable options for the communication with an SCGI application.
+pub struct ScgiSettings {
+    /// Maximum time spent connecting to the application, and waiting for it
+    /// to accept or send data.
+    pub timeout: Duration,
+    /// Request headers which are not passed to the application as `HTTP_*`
+    /// headers (see `CgiSettings::excluded_headers`).
+    pub excluded_headers: Vec<HeaderName>,
+    /// Whether error responses describe what went wrong with the
+    /// application. Meant for development only.
+    pub detailed_errors: bool,
+}
+
+impl Default for ScgiSettings {
+    fn default() -> ScgiSettings {
+        ScgiSettings {
+            timeout: DEFAULT_TIMEOUT,
+            excluded_headers: default_excluded_headers(),
+            detailed_errors: false,
+        }
+    }
+}
+
+/// Encodes the request metavariables into the netstring starting an SCGI
+/// request. As required by the SCGI specification, `CONTENT_LENGTH` comes
+/// first (and is always set), followed by `SCGI` set to `1`.
+///
+fn encode_headers(metavariables: &CGIMetavariableMap, content_length: usize) -> Vec<u8> {
+    let mut headers = Vec::new();
+    let mut push_header = |name: &str, value: &str| {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    };
+
+    push_header("CONTENT_LENGTH", &content_length.to_string());
+    push_header("SCGI", "1");
+    for (name, value) in metavariables {
+        if *name != CGIMetavariable::ContentLength {
+            push_header(name.name(), value);
+        }
+    }
+
+    let mut netstring = format!("{}:", headers.len()).into_bytes();
+    netstring.append(&mut headers);
+    netstring.push(b',');
+    netstring
+}
+
+/// Output of an SCGI request, which is the rest of the connection. Timeouts
+/// are recorded, since they are otherwise reported as invalid output.
+struct ScgiResponseReader {
+    connection: BackendStream,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Read for ScgiResponseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.connection.read(buf).inspect_err(|error| {
+            if is_timeout(error) {
+                self.timed_out.store(true, Ordering::SeqCst);
+            }
+        })
+    }
+}
+
+/// Request handler forwarding the requests under a given path to an SCGI
+/// application. Each request uses its own connection, which the application
+/// closes once its response is over.
+pub struct ScgiRequestHandler {
+    path: String,
+    address: BackendAddress,
+    static_folder: String,
+    settings: ScgiSettings,
+}
+
+impl ScgiRequestHandler {
+    pub fn new(
+        path: String,
+        address: BackendAddress,
+        static_folder: String,
+        settings: ScgiSettings,
+    ) -> ScgiRequestHandler {
+        ScgiRequestHandler {
+            path,
+            address,
+            static_folder,
+            settings,
+        }
+    }
+
+    /// Forwards the request to the application and converts its output into
+    /// an HTTP response.
+    ///
+    fn forward_request(
+        &self,
+        stream: &TcpStream,
+        request: &Request<Vec<u8>>,
+        script_name: &str,
+        path_info: &str,
+    ) -> Result<Response<ResponseBody>, CgiError> {
+        let metavariables = generate_environment_variables(
+            stream,
+            request,
+            script_name,
+            path_info,
+            &self.static_folder,
+            &self.settings.excluded_headers,
+        );
+        let headers = encode_headers(&metavariables, request.body().len());
+
+        debug!("Connecting to the SCGI application at {:?}", self.address);
+        let mut connection = BackendStream::connect(&self.address, self.settings.timeout)
+            .map_err(CgiError::ConnectionFailed)?;
+
+        let mut writer = BufWriter::new(&mut connection);
+        let sent = writer
+            .write_all(&headers)
+            .and_then(|()| writer.write_all(request.body()))
+            .and_then(|()| writer.flush());
+        drop(writer);
+        if let Err(error) = sent {
+            return Err(if is_timeout(&error) {
+                CgiError::Timeout
+            } else {
+                CgiError::ConnectionFailed(error)
+            });
+        }
+
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let reader = ScgiResponseReader {
+            connection,
+            timed_out: Arc::clone(&timed_out),
+        };
+        let cgi_response = match read_cgi_response(reader) {
+            Err(_) if timed_out.load(Ordering::SeqCst) => return Err(CgiError::Timeout),
+            Err(error) => return Err(error),
+            Ok(cgi_response) => cgi_response,
+        };
+
+        debug!("SCGI headers: {:?}", cgi_response.headers());
+        convert_cgi_response_to_http(cgi_response)
+    }
+}
+
+impl RequestHandler<Vec<u8>> for ScgiRequestHandler {
+    /// Handles an incoming request. If the requested path is under the
+    /// application path, forwards the request to the SCGI application and
+    /// returns its response. Otherwise returns a `None` value so that the
+    /// next handler can try to process the request.
+    ///
+    fn handle_request(
+        &self,
+        stream: &TcpStream,
+        request: &Request<Vec<u8>>,
+    ) -> Option<Response<ResponseBody>> {
+        let (script_name, path_info) = split_path(&self.path, request.uri().path())?;
+
+        match self.forward_request(stream, request, script_name, path_info) {
+            Err(error) => {
+                warn!("SCGI request to {} failed: {}", script_name, error);
+                debug!("SCGI error: {:?}", error);
+                Some(error.to_response(self.settings.detailed_errors))
+            }
+            Ok(response) => Some(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{net::TcpListener, thread};
+
+    use http::StatusCode;
+
+    use crate::http_server::request::test_support::run_request;
+
+    /// Reads an SCGI request from the given connection, returning its
+    /// headers (in order) and body.
+    ///
+    fn read_request(connection: &mut TcpStream) -> (Vec<(String, String)>, Vec<u8>) {
+        let mut length = Vec::new();
+        let mut byte = [0];
+        while connection.read(&mut byte).unwrap() == 1 && byte[0] != b':' {
+            length.push(byte[0]);
+        }
+        let length: usize = String::from_utf8(length).unwrap().parse().unwrap();
+
+        let mut headers = vec![0; length + 1];
+        connection.read_exact(&mut headers).unwrap();
+        assert_eq!(headers.pop(), Some(b','));
+        let fields = headers
+            .split(|byte| *byte == 0)
+            .map(|field| String::from_utf8(field.to_vec()).unwrap())
+            .collect::<Vec<_>>();
+        let headers = fields
+            .chunks_exact(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect::<Vec<_>>();
+
+        let content_length: usize = headers[0].1.parse().unwrap();
+        let mut body = vec![0; content_length];
+        connection.read_exact(&mut body).unwrap();
+
+        (headers, body)
+    }
+
+    #[test]
+    fn requests_are_sent_as_netstrings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let application = thread::spawn(move || {
+            let mut connection = listener.accept().unwrap().0;
+            let (headers, body) = read_request(&mut connection);
+            let header = |name: &str| {
+                headers
+                    .iter()
+                    .find(|(header_name, _)| header_name == name)
+                    .map_or(String::new(), |(_, value)| value.clone())
+            };
+
+            write!(
+                connection,
+                "Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n{} {}{} {}",
+                header("REQUEST_METHOD"),
+                header("SCRIPT_NAME"),
+                header("PATH_INFO"),
+                String::from_utf8_lossy(&body)
+            )
+            .unwrap();
+            headers
+        });
+
+        let handler = ScgiRequestHandler::new(
+            String::from("tools"),
+            BackendAddress::parse(&address),
+            String::from("public_html"),
+            ScgiSettings::default(),
+        );
+
+        assert_eq!(
+            run_request(&handler, "/tools/report", b"name=test"),
+            (
+                StatusCode::CREATED,
+                String::from("POST /tools/report name=test")
+            )
+        );
+
+        let headers = application.join().unwrap();
+        assert_eq!(
+            headers[..2],
+            [
+                (String::from("CONTENT_LENGTH"), String::from("9")),
+                (String::from("SCGI"), String::from("1")),
+            ]
+        );
+        assert_eq!(
+            headers
+                .iter()
+                .filter(|(name, _)| name == "CONTENT_LENGTH")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn silent_application_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut connection = listener.accept().unwrap().0;
+            read_request(&mut connection);
+            thread::sleep(Duration::from_secs(2));
+        });
+
+        let handler = ScgiRequestHandler::new(
+            String::from(""),
+            BackendAddress::parse(&address),
+            String::from("public_html"),
+            ScgiSettings {
+                timeout: Duration::from_millis(200),
+                ..ScgiSettings::default()
+            },
+        );
+
+        assert_eq!(
+            run_request(&handler, "/", b"").0,
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+}
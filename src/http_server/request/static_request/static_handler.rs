@@ -1,22 +1,43 @@
-use std::{fs, net::TcpStream, path::Path};
+use std::{
+    fs::{self, File},
+    net::TcpStream,
+    path::Path,
+};
 
 use http::{Request, Response, StatusCode};
 
 use log::debug;
 
-use crate::http_server::{request::request::RequestHandler, response::generate_error_response};
+use crate::http_server::{
+    request::request::RequestHandler,
+    response::{generate_error_response, ResponseBody},
+};
 
 pub struct StaticRequestHandler {
     static_folder: String,
+    hidden_extensions: Vec<String>,
 }
 
 impl StaticRequestHandler {
-    pub fn new(static_folder: String) -> StaticRequestHandler {
-        StaticRequestHandler { static_folder }
+    /// Creates a handler serving the files of `static_folder`. Files with one
+    /// of the `hidden_extensions` (such as scripts mapped to an interpreter)
+    /// are never served, so that their source isn't disclosed.
+    ///
+    pub fn new(static_folder: String, hidden_extensions: Vec<String>) -> StaticRequestHandler {
+        StaticRequestHandler {
+            static_folder,
+            hidden_extensions,
+        }
+    }
+
+    /// Returns the folder the static files are served from.
+    ///
+    pub fn static_folder(&self) -> &str {
+        &self.static_folder
     }
 }
 
-impl RequestHandler<String> for StaticRequestHandler {
+impl RequestHandler<Vec<u8>> for StaticRequestHandler {
     /// Handles an incoming request. Always returns a `Some` variant, since the
     /// static handler is kind of a fallback handler. If the requested page
     /// isn't available, it should return a 404 response.
@@ -32,8 +53,8 @@ impl RequestHandler<String> for StaticRequestHandler {
     fn handle_request(
         &self,
         _stream: &TcpStream,
-        request: &Request<String>,
-    ) -> Option<Response<String>> {
+        request: &Request<Vec<u8>>,
+    ) -> Option<Response<ResponseBody>> {
         if request.method() != "GET" && request.method() != "HEAD" {
             return Some(generate_error_response(StatusCode::METHOD_NOT_ALLOWED));
         }
@@ -59,19 +80,35 @@ impl RequestHandler<String> for StaticRequestHandler {
             }
         };
 
+        let is_hidden = abs_file_path.extension().is_some_and(|extension| {
+            self.hidden_extensions
+                .iter()
+                .any(|hidden_extension| extension == hidden_extension.as_str())
+        });
+        if is_hidden {
+            debug!("Refusing to serve hidden file {:?}", abs_file_path);
+            return Some(generate_error_response(StatusCode::NOT_FOUND));
+        }
+
         debug!("Searching for {:?}", abs_file_path);
-        let contents = fs::read_to_string(abs_file_path);
-        debug!("Read result: {:?}", contents);
-        let contents = match contents {
+        let file = File::open(abs_file_path).and_then(|file| {
+            let metadata = file.metadata()?;
+            Ok((file, metadata))
+        });
+        debug!("Open result: {:?}", file);
+        let (file, contents_len) = match file {
             Err(_) => return Some(generate_error_response(StatusCode::INTERNAL_SERVER_ERROR)),
-            Ok(contents) => contents,
+            // Directories and other special files aren't served
+            Ok((_, metadata)) if !metadata.is_file() => {
+                return Some(generate_error_response(StatusCode::NOT_FOUND));
+            }
+            Ok((file, metadata)) => (file, metadata.len()),
         };
 
-        let contents_len = contents.len();
         let sent_content = if request.method() == "HEAD" {
-            String::from("")
+            ResponseBody::empty()
         } else {
-            contents
+            ResponseBody::from_reader(file, Some(contents_len))
         };
 
         Some(
@@ -83,3 +120,27 @@ impl RequestHandler<String> for StaticRequestHandler {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    #[test]
+    fn files_with_hidden_extensions_are_not_served() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let handler = StaticRequestHandler::new(
+            String::from("public_html"),
+            vec![String::from("py"), String::from("php")],
+        );
+        let get = |uri: &str| {
+            let request = Request::get(uri).body(Vec::new()).unwrap();
+            handler.handle_request(&stream, &request).unwrap().status()
+        };
+
+        assert_eq!(get("/hello.py"), StatusCode::NOT_FOUND);
+        assert_eq!(get("/index.html"), StatusCode::OK);
+    }
+}
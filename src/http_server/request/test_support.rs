@@ -0,0 +1,42 @@
+use std::{
+    io::Read,
+    net::{TcpListener, TcpStream},
+};
+
+use http::{Request, StatusCode};
+
+use crate::http_server::{request::request::RequestHandler, response::ResponseBody};
+
+/// Returns a connected TCP stream, standing for the client connection
+/// requests are read from.
+///
+pub fn client_stream() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    TcpStream::connect(listener.local_addr().unwrap()).unwrap()
+}
+
+/// Sends a POST request with the given URI and body to `handler`, and
+/// returns the status and body of its response (which must not be raw).
+///
+pub fn run_request<H: RequestHandler<Vec<u8>>>(
+    handler: &H,
+    uri: &str,
+    body: &[u8],
+) -> (StatusCode, String) {
+    let request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .body(body.to_vec())
+        .unwrap();
+    let response = handler.handle_request(&client_stream(), &request).unwrap();
+
+    let status = response.status();
+    let mut body = Vec::new();
+    match response.into_body() {
+        ResponseBody::Stream { mut reader, .. } => reader.read_to_end(&mut body).unwrap(),
+        ResponseBody::Bytes(bytes) => bytes.len(),
+        ResponseBody::Raw(_) => panic!("Application responses should not be raw"),
+    };
+
+    (status, String::from_utf8(body).unwrap())
+}
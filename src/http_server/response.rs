@@ -1,36 +1,541 @@
-use http::{Response, StatusCode};
+use std::{
+    fmt,
+    io::{self, prelude::*, BufWriter},
+};
+
+use http::{header, HeaderMap, HeaderName, HeaderValue, Response, StatusCode, Version};
+
+use log::warn;
+
+/// Headers which only make sense for a single HTTP connection, and are
+/// therefore never passed along from a CGI script output or between a proxy
+/// client and its upstream server.
+pub const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Body of an HTTP response. Small bodies are kept in memory, while larger
+/// ones (such as files or CGI program outputs) are read from a stream and
+/// written to the client as they are read, so memory usage doesn't depend on
+/// the size of the response.
+///
+/// `Raw` bodies are a special case: the reader supplies the whole HTTP
+/// response (status line and headers included), which is written to the
+/// client untouched. Since the server doesn't know how such a response is
+/// framed, the connection is closed once it has been written.
+pub enum ResponseBody {
+    Bytes(Vec<u8>),
+    Stream {
+        reader: Box<dyn Read + Send>,
+        length: Option<u64>,
+    },
+    Raw(Box<dyn Read + Send>),
+}
+
+impl ResponseBody {
+    pub fn empty() -> ResponseBody {
+        ResponseBody::Bytes(Vec::new())
+    }
+
+    /// Creates a body which streams data from the given reader. `length`
+    /// should be given if the amount of data is known in advance.
+    ///
+    pub fn from_reader<R: Read + Send + 'static>(reader: R, length: Option<u64>) -> ResponseBody {
+        ResponseBody::Stream {
+            reader: Box::new(reader),
+            length,
+        }
+    }
+
+    /// Creates a body which supplies the whole HTTP response, status line and
+    /// headers included, from the given reader.
+    ///
+    pub fn raw<R: Read + Send + 'static>(reader: R) -> ResponseBody {
+        ResponseBody::Raw(Box::new(reader))
+    }
+
+    /// Returns the length of the body, if known in advance.
+    ///
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            ResponseBody::Bytes(bytes) => Some(bytes.len() as u64),
+            ResponseBody::Stream { length, .. } => *length,
+            ResponseBody::Raw(_) => None,
+        }
+    }
+
+    /// Returns whether the body is known to be empty.
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Returns whether the body supplies the whole HTTP response.
+    ///
+    pub fn is_raw(&self) -> bool {
+        matches!(self, ResponseBody::Raw(_))
+    }
+}
+
+impl From<Vec<u8>> for ResponseBody {
+    fn from(bytes: Vec<u8>) -> ResponseBody {
+        ResponseBody::Bytes(bytes)
+    }
+}
+
+impl fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseBody::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            ResponseBody::Stream { length, .. } => write!(f, "Stream(length: {length:?})"),
+            ResponseBody::Raw(_) => write!(f, "Raw"),
+        }
+    }
+}
+
+/// Response extension set by request handlers which want the request to be
+/// processed again with a different URI, without the client being involved
+/// (such as CGI local redirects). The connection handler takes care of
+/// re-dispatching the request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalRedirect {
+    /// Path (and optionally query string) the request is redirected to.
+    pub location: String,
+}
+
+/// Writer which encodes everything written to it with the chunked transfer
+/// coding. `finish` must be called to write the last chunk.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        write!(self.inner, "{:X}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader which only supplies the status line and headers of a raw HTTP
+/// response, stopping at the blank line which ends them. Used to answer HEAD
+/// requests with raw bodies, since the client doesn't expect a body then.
+struct RawHeadReader<R: Read> {
+    inner: R,
+    at_line_start: bool,
+    done: bool,
+}
+
+impl<R: Read> RawHeadReader<R> {
+    fn new(inner: R) -> RawHeadReader<R> {
+        RawHeadReader {
+            inner,
+            at_line_start: false,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Read for RawHeadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+
+        let read_bytes = self.inner.read(buf)?;
+        for (index, byte) in buf[..read_bytes].iter().enumerate() {
+            match byte {
+                b'\n' if self.at_line_start => {
+                    self.done = true;
+                    return Ok(index + 1);
+                }
+                b'\n' => self.at_line_start = true,
+                b'\r' => (),
+                _ => self.at_line_start = false,
+            }
+        }
+
+        Ok(read_bytes)
+    }
+}
+
+/// Copies all data from `reader` to `writer`, flushing the writer after each
+/// read. Used for streams of unknown length (such as CGI outputs), whose
+/// data should reach the client as soon as it is produced.
+///
+fn copy_as_produced<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<()> {
+    let mut buffer = [0; 8 * 1024];
+
+    loop {
+        let read_bytes = match reader.read(&mut buffer) {
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+            Ok(0) => return Ok(()),
+            Ok(read_bytes) => read_bytes,
+        };
+
+        writer.write_all(&buffer[..read_bytes])?;
+        writer.flush()?;
+    }
+}
+
+/// Removes the hop-by-hop headers from the given headers, including the ones
+/// listed in the `Connection` header.
+///
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let connection_options = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|option| HeaderName::from_bytes(option.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for header_name in HOP_BY_HOP_HEADERS.iter().chain(&connection_options) {
+        headers.remove(header_name);
+    }
+}
 
 /// Generates an empty HTTP response with a given status code
 ///
-pub fn generate_error_response(status_code: StatusCode) -> Response<String> {
-    let mut response = Response::new(String::from(""));
+pub fn generate_error_response(status_code: StatusCode) -> Response<ResponseBody> {
+    let mut response = Response::new(ResponseBody::empty());
     *response.status_mut() = status_code;
 
-    return response;
+    response
 }
 
-/// Converts a structured HTTP response into the text data to be sent back to
-/// the requesting client
+/// Sets the headers describing how the end of the response body will be
+/// detected by a client using the given HTTP version. Bodies of known length
+/// get a `Content-Length` header (unless the handler which generated the
+/// response already set it). Streams of unknown length are sent with the
+/// chunked transfer coding to HTTP/1.1 clients, while older clients can only
+/// detect the end of the body when the connection is closed.
+///
+/// A declared `Content-Length` which doesn't match the length of the body
+/// would make the client read the wrong amount of data, so such responses
+/// are replaced with an INTERNAL SERVER ERROR response. Responses to HEAD
+/// requests (`is_head`) and NOT MODIFIED responses are the exception, since
+/// their `Content-Length` describes a body which isn't sent. The body of
+/// responses to HEAD requests is dropped once their headers are set.
+///
+/// Informational (1xx) and NO CONTENT responses never have a body, nor any
+/// framing headers (section 8.6 of RFC 9110), so their body is dropped.
+///
+/// Returns `false` if the body is delimited by closing the connection, in
+/// which case the connection can't be kept open after the response. This is
+/// always the case for raw bodies, whose framing is left untouched (though
+/// only their status line and headers are sent in response to HEAD
+/// requests).
+///
+pub fn set_body_framing(
+    response: &mut Response<ResponseBody>,
+    version: Version,
+    is_head: bool,
+) -> bool {
+    if response.body().is_raw() {
+        if is_head {
+            if let ResponseBody::Raw(reader) =
+                std::mem::replace(response.body_mut(), ResponseBody::empty())
+            {
+                *response.body_mut() = ResponseBody::raw(RawHeadReader::new(reader));
+            }
+        }
+        return false;
+    }
+
+    if response.status().is_informational() || response.status() == StatusCode::NO_CONTENT {
+        let headers = response.headers_mut();
+        headers.remove(header::CONTENT_LENGTH);
+        headers.remove(header::TRANSFER_ENCODING);
+        *response.body_mut() = ResponseBody::empty();
+        return true;
+    }
+
+    let declared_length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if let ResponseBody::Stream { length, .. } = response.body_mut() {
+        *length = length.or(declared_length);
+    }
+
+    let body_length = response.body().len();
+    let omits_body = is_head || response.status() == StatusCode::NOT_MODIFIED;
+    if let (Some(body_length), Some(declared_length)) = (body_length, declared_length) {
+        if body_length != declared_length && !omits_body {
+            warn!(
+                "Response body is {} bytes long, but its Content-Length is {}",
+                body_length, declared_length
+            );
+            *response = generate_error_response(StatusCode::INTERNAL_SERVER_ERROR);
+            return set_body_framing(response, version, is_head);
+        }
+    }
+
+    let headers = response.headers_mut();
+    headers.remove(header::TRANSFER_ENCODING);
+
+    let is_delimited = match body_length {
+        Some(body_length) => {
+            headers
+                .entry(header::CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(body_length));
+            true
+        }
+        None if version >= Version::HTTP_11 => {
+            headers.remove(header::CONTENT_LENGTH);
+            headers.insert(
+                header::TRANSFER_ENCODING,
+                HeaderValue::from_static("chunked"),
+            );
+            true
+        }
+        None => {
+            headers.remove(header::CONTENT_LENGTH);
+            false
+        }
+    };
+
+    if is_head {
+        *response.body_mut() = ResponseBody::empty();
+    }
+
+    is_delimited
+}
+
+/// Writes a structured HTTP response to the given writer (usually the TCP
+/// stream of the requesting client). Streamed bodies are copied to the writer
+/// as they are read, and an error is returned if a stream of known length
+/// ends early, since the client would otherwise wait for the missing data
+/// (or take the next response for it). Streams of unknown length are encoded with the chunked
+/// transfer coding if the response headers say so, and are otherwise written
+/// as they are, to be delimited by closing the connection. Raw bodies are
+/// written as they are read, without a status line or headers.
+/// `set_body_framing` should be called beforehand so that the headers match
+/// the way the body is sent.
 ///
 /// # Panics
 ///
-/// The `response_to_string` function will panic if the given response has any
+/// The `write_response` function will panic if the given response has any
 /// headers that can't be converted to a string.
 ///
-pub fn response_to_string(response: Response<String>) -> String {
-    let status = response.status();
-    let status_value = status.as_str();
-    let reason = status.canonical_reason().unwrap_or("");
-    let status_line = format!("HTTP/1.1 {status_value} {reason}");
+pub fn write_response<W: Write>(writer: W, response: Response<ResponseBody>) -> io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+
+    if !response.body().is_raw() {
+        let status = response.status();
+        let status_value = status.as_str();
+        let reason = status.canonical_reason().unwrap_or("");
+        write!(writer, "HTTP/1.1 {status_value} {reason}\r\n")?;
+
+        for (header_name, header_value) in response.headers() {
+            let header_value = header_value.to_str().expect("Invalid response header");
+            write!(writer, "{header_name}: {header_value}\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+    }
+
+    let is_chunked = response
+        .headers()
+        .get(header::TRANSFER_ENCODING)
+        .is_some_and(|value| value == "chunked");
+
+    match response.into_body() {
+        ResponseBody::Bytes(bytes) => writer.write_all(&bytes)?,
+        ResponseBody::Stream {
+            reader,
+            length: Some(length),
+        } => {
+            let copied_length = io::copy(&mut reader.take(length), &mut writer)?;
+            if copied_length < length {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("response body ended after {copied_length} of {length} bytes"),
+                ));
+            }
+        }
+        ResponseBody::Stream {
+            mut reader,
+            length: None,
+        } if is_chunked => {
+            let mut chunked_writer = ChunkedWriter::new(&mut writer);
+            copy_as_produced(&mut reader, &mut chunked_writer)?;
+            chunked_writer.finish()?;
+        }
+        ResponseBody::Stream {
+            mut reader,
+            length: None,
+        } => {
+            copy_as_produced(&mut reader, &mut writer)?;
+        }
+        ResponseBody::Raw(mut reader) => {
+            copy_as_produced(&mut reader, &mut writer)?;
+        }
+    }
+
+    writer.flush()
+}
 
-    let mut header_line = String::from("");
-    for (header_name, header_value) in response.headers() {
-        let header_value = header_value.to_str().expect("Invalid response header");
-        let header = format!("{header_name}: {header_value}\r\n");
-        header_line.push_str(&header);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::http_server::request::cgi_request::cgi_response::{
+        convert_cgi_response_to_http, parse_cgi_response,
+    };
+
+    #[test]
+    fn stream_of_unknown_length_is_chunked() {
+        let mut response = Response::new(ResponseBody::from_reader(&b"Hello, World"[..], None));
+        assert!(set_body_framing(&mut response, Version::HTTP_11, false));
+
+        let mut output = Vec::new();
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\nC\r\nHello, World\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn stream_of_known_length_is_copied() {
+        let mut response = Response::new(ResponseBody::from_reader(&b"Hello"[..], Some(5)));
+        assert!(set_body_framing(&mut response, Version::HTTP_10, false));
+
+        let mut output = Vec::new();
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nHello"
+        );
     }
 
-    let contents = response.body();
+    #[test]
+    fn stream_of_unknown_length_is_close_delimited_for_http_10() {
+        let mut response = Response::new(ResponseBody::from_reader(&b"Hello, World"[..], None));
+        assert!(!set_body_framing(&mut response, Version::HTTP_10, false));
+
+        let mut output = Vec::new();
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\n\r\nHello, World"
+        );
+    }
+
+    #[test]
+    fn raw_body_is_written_untouched() {
+        let raw_response = "HTTP/1.1 202 Accepted\r\nContent-Type: text/plain\r\n\r\nQueued";
+        let mut response = Response::new(ResponseBody::raw(raw_response.as_bytes()));
+        assert!(!set_body_framing(&mut response, Version::HTTP_11, false));
+        response
+            .headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+
+        let mut output = Vec::new();
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), raw_response);
+    }
 
-    format!("{status_line}\r\n{header_line}\r\n{contents}")
+    #[test]
+    fn raw_body_is_cut_after_headers_for_head_requests() {
+        let raw_response = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n3...\n2...\n";
+        let mut response = Response::new(ResponseBody::raw(raw_response.as_bytes()));
+        assert!(!set_body_framing(&mut response, Version::HTTP_11, true));
+
+        let mut output = Vec::new();
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn no_content_response_has_no_framing_headers() {
+        let cgi_response = parse_cgi_response(b"Status: 204 No Content\n\n".to_vec()).unwrap();
+        let mut response = convert_cgi_response_to_http(cgi_response).unwrap();
+        assert!(set_body_framing(&mut response, Version::HTTP_11, false));
+
+        let mut output = Vec::new();
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 204 No Content\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn declared_content_length_is_honored() {
+        let mut response = Response::builder()
+            .header("content-length", "5")
+            .body(ResponseBody::from_reader(&b"Hello"[..], None))
+            .unwrap();
+        assert!(set_body_framing(&mut response, Version::HTTP_11, false));
+
+        assert_eq!(response.body().len(), Some(5));
+        assert!(response.headers().get(header::TRANSFER_ENCODING).is_none());
+    }
+
+    #[test]
+    fn declared_content_length_is_enforced() {
+        let mut response = Response::builder()
+            .header("content-length", "100")
+            .body(ResponseBody::from(b"Hello".to_vec()))
+            .unwrap();
+        assert!(set_body_framing(&mut response, Version::HTTP_11, false));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "0");
+
+        let mut response = Response::builder()
+            .header("content-length", "100")
+            .body(ResponseBody::empty())
+            .unwrap();
+        assert!(set_body_framing(&mut response, Version::HTTP_11, true));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "100");
+
+        let mut response = Response::builder()
+            .header("content-length", "100")
+            .body(ResponseBody::from_reader(&b"Hello"[..], None))
+            .unwrap();
+        assert!(set_body_framing(&mut response, Version::HTTP_11, false));
+        assert!(write_response(&mut Vec::new(), response).is_err());
+    }
 }
@@ -1,10 +1,30 @@
+use std::collections::HashMap;
+use std::fs;
 use std::net::TcpListener;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+
+use log::Level;
 
 use rust_web_cgi::http_server::{
-    connection::ConnectionHandler,
+    connection::{ConnectionHandler, ConnectionSettings, RequestHandlerList},
     request::{
-        cgi_request::cgi_handler::CgiRequestHandler,
+        backend::BackendAddress,
+        cgi_request::{
+            cgi_error_log::CgiErrorLog,
+            cgi_handler::{CgiRequestHandler, CgiSettings},
+            cgi_process::{CgiProgram, CgiResourceLimits},
+        },
+        fastcgi_request::{
+            fastcgi_handler::{FastCgiRequestHandler, FastCgiSettings},
+            fastcgi_process_manager::{FastCgiProcessManager, FastCgiProcessSettings},
+        },
+        proxy_request::{
+            proxy_balancer::{BalancerSettings, BalancingStrategy, HealthCheck},
+            proxy_handler::{ProxyRequestHandler, ProxySettings},
+        },
+        scgi_request::scgi_handler::{ScgiRequestHandler, ScgiSettings},
         static_request::static_handler::StaticRequestHandler,
     },
 };
@@ -16,6 +36,160 @@ const POOL_SIZE: usize = 4;
 const STATIC_FOLDER: &str = "public_html";
 const CGI_FOLDER: &str = "cgi-bin";
 const CGI_PATH: &str = "cgi-bin";
+const CGI_TIMEOUT: Duration = Duration::from_secs(30);
+const CGI_CPU_SECONDS: u64 = 10;
+const CGI_ADDRESS_SPACE: u64 = 1024 * 1024 * 1024; // 1GB
+const CGI_OPEN_FILES: u64 = 256;
+const CGI_FILE_SIZE: u64 = 64 * 1024 * 1024; // 64MB
+const CGI_MAX_OUTPUT_SIZE: u64 = 256 * 1024 * 1024; // 256MB
+const CGI_STDERR_LOG_LEVEL: Level = Level::Warn;
+const CGI_ERROR_LOG: Option<&str> = Some("cgi_error.log");
+const CGI_ERROR_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024; // 10MB
+const CGI_INTERPRETERS: [(&str, &str); 3] = [("py", "python3"), ("pl", "perl"), ("php", "php-cgi")];
+// Whether scripts with a mapped interpreter are also run from STATIC_FOLDER
+const CGI_IN_STATIC_FOLDER: bool = false;
+// Detailed CGI error pages are only shown in development (debug) builds
+const CGI_DETAILED_ERRORS: bool = cfg!(debug_assertions);
+
+// Address of a FastCGI application ("host:port" or "unix:/path/to/socket")
+// served at FASTCGI_PATH, if any
+const FASTCGI_ADDRESS: Option<&str> = None;
+const FASTCGI_PATH: &str = "fastcgi";
+const FASTCGI_TIMEOUT: Duration = Duration::from_secs(30);
+const FASTCGI_MAX_IDLE_CONNECTIONS: usize = 4;
+// FastCGI application started and supervised by the server instead, served
+// at FASTCGI_PATH, if any (its interpreter is picked from CGI_INTERPRETERS)
+const FASTCGI_SCRIPT: Option<&str> = None;
+const FASTCGI_MIN_PROCESSES: usize = 1;
+const FASTCGI_MAX_PROCESSES: usize = 4;
+const FASTCGI_MAX_REQUESTS_PER_PROCESS: u64 = 1000;
+const FASTCGI_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Address of an SCGI application ("host:port" or "unix:/path/to/socket")
+// served at SCGI_PATH, if any
+const SCGI_ADDRESS: Option<&str> = None;
+const SCGI_PATH: &str = "scgi";
+const SCGI_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Path prefixes forwarded to upstream HTTP servers, such as
+// ("api", &["127.0.0.1:3000", "127.0.0.1:3001"]) or ("app", &["unix:/run/app.sock"])
+const PROXY_ROUTES: &[(&str, &[&str])] = &[];
+// Whether the prefix is removed from the path of forwarded requests
+const PROXY_STRIP_PREFIX: bool = false;
+const PROXY_TIMEOUT: Duration = Duration::from_secs(30);
+// How requests are spread across the upstream servers of a route
+const PROXY_BALANCING: BalancingStrategy = BalancingStrategy::RoundRobin;
+// Failed requests in a row after which an upstream server is skipped, and for how long
+const PROXY_MAX_FAILURES: usize = 3;
+const PROXY_FAILURE_TIMEOUT: Duration = Duration::from_secs(10);
+// Path periodically requested from each upstream server, which is skipped
+// while it doesn't answer with a 2xx or 3xx status
+const PROXY_HEALTH_CHECK_PATH: Option<&str> = None;
+const PROXY_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const MAX_BODY_SIZE: usize = 1024 * 1024; // 1MB
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+// Connections kept open between requests at once, leaving a worker thread
+// free for the other clients
+const MAX_PERSISTENT_CONNECTIONS: usize = POOL_SIZE - 1;
+const MAX_LOCAL_REDIRECTS: usize = 10;
+
+/// Returns the extensions of the scripts mapped to an interpreter, which are
+/// never served as static files.
+///
+fn script_extensions() -> Vec<String> {
+    CGI_INTERPRETERS
+        .iter()
+        .map(|(extension, _)| String::from(*extension))
+        .collect()
+}
+
+/// Builds the CGI settings shared by the CGI handlers. `add_handler` is set
+/// for the handler running scripts found among the static files.
+///
+fn cgi_settings(error_log: &Option<Arc<CgiErrorLog>>, add_handler: bool) -> CgiSettings {
+    CgiSettings {
+        timeout: CGI_TIMEOUT,
+        resource_limits: CgiResourceLimits {
+            cpu_seconds: Some(CGI_CPU_SECONDS),
+            address_space: Some(CGI_ADDRESS_SPACE),
+            open_files: Some(CGI_OPEN_FILES),
+            processes: None,
+            file_size: Some(CGI_FILE_SIZE),
+        },
+        max_output_size: Some(CGI_MAX_OUTPUT_SIZE),
+        stderr_log_level: CGI_STDERR_LOG_LEVEL,
+        error_log: error_log.clone(),
+        interpreters: HashMap::from(
+            CGI_INTERPRETERS.map(|(extension, interpreter)| {
+                (String::from(extension), String::from(interpreter))
+            }),
+        ),
+        add_handler,
+        detailed_errors: CGI_DETAILED_ERRORS,
+        ..CgiSettings::default()
+    }
+}
+
+/// Builds the settings of the FastCGI handler.
+///
+fn fastcgi_settings(error_log: &Option<Arc<CgiErrorLog>>) -> FastCgiSettings {
+    FastCgiSettings {
+        timeout: FASTCGI_TIMEOUT,
+        max_idle_connections: FASTCGI_MAX_IDLE_CONNECTIONS,
+        stderr_log_level: CGI_STDERR_LOG_LEVEL,
+        error_log: error_log.clone(),
+        detailed_errors: CGI_DETAILED_ERRORS,
+        ..FastCgiSettings::default()
+    }
+}
+
+/// Starts the manager of the FastCGI application processes running the given
+/// script.
+///
+/// # Panics
+///
+/// The `fastcgi_process_manager` function panics if the script does not
+/// exist.
+///
+fn fastcgi_process_manager(
+    script: &str,
+    error_log: &Option<Arc<CgiErrorLog>>,
+) -> FastCgiProcessManager {
+    let script_path = fs::canonicalize(script).expect("FastCGI script does not exist");
+    let interpreter = script_path
+        .extension()
+        .and_then(|extension| {
+            CGI_INTERPRETERS
+                .iter()
+                .find(|(mapped_extension, _)| extension == *mapped_extension)
+        })
+        .map(|(_, interpreter)| String::from(*interpreter));
+
+    FastCgiProcessManager::new(
+        CgiProgram {
+            script_path,
+            interpreter,
+        },
+        FastCgiProcessSettings {
+            min_processes: FASTCGI_MIN_PROCESSES,
+            max_processes: FASTCGI_MAX_PROCESSES,
+            max_requests_per_process: Some(FASTCGI_MAX_REQUESTS_PER_PROCESS),
+            idle_timeout: FASTCGI_IDLE_TIMEOUT,
+            resource_limits: CgiResourceLimits {
+                cpu_seconds: None,
+                address_space: Some(CGI_ADDRESS_SPACE),
+                open_files: Some(CGI_OPEN_FILES),
+                processes: None,
+                file_size: Some(CGI_FILE_SIZE),
+            },
+            stderr_log_level: CGI_STDERR_LOG_LEVEL,
+            error_log: error_log.clone(),
+            ..FastCgiProcessSettings::default()
+        },
+    )
+}
 
 fn main() {
     env_logger::init();
@@ -23,14 +197,93 @@ fn main() {
     let listener = TcpListener::bind(ADDR_AND_PORT).unwrap();
     let pool = ThreadPool::new(POOL_SIZE);
 
-    let conn_handler = Arc::new(ConnectionHandler::new(vec![
-        Box::new(CgiRequestHandler::new(
-            String::from(CGI_PATH),
-            String::from(CGI_FOLDER),
-            StaticRequestHandler::new(String::from(STATIC_FOLDER)),
-        )),
-        Box::new(StaticRequestHandler::new(String::from(STATIC_FOLDER))),
-    ]));
+    let error_log = CGI_ERROR_LOG.map(|path| {
+        Arc::new(CgiErrorLog::new(
+            PathBuf::from(path),
+            CGI_ERROR_LOG_MAX_SIZE,
+        ))
+    });
+
+    let mut request_handlers: RequestHandlerList = Vec::new();
+    for (prefix, upstreams) in PROXY_ROUTES {
+        request_handlers.push(Box::new(ProxyRequestHandler::new(
+            String::from(*prefix),
+            upstreams
+                .iter()
+                .map(|upstream| BackendAddress::parse(upstream))
+                .collect(),
+            ProxySettings {
+                timeout: PROXY_TIMEOUT,
+                strip_prefix: PROXY_STRIP_PREFIX,
+                balancer: BalancerSettings {
+                    strategy: PROXY_BALANCING,
+                    max_failures: PROXY_MAX_FAILURES,
+                    failure_timeout: PROXY_FAILURE_TIMEOUT,
+                    health_check: PROXY_HEALTH_CHECK_PATH.map(|path| HealthCheck {
+                        path: String::from(path),
+                        interval: PROXY_HEALTH_CHECK_INTERVAL,
+                        timeout: PROXY_TIMEOUT,
+                    }),
+                },
+            },
+        )));
+    }
+    if let Some(fastcgi_script) = FASTCGI_SCRIPT {
+        request_handlers.push(Box::new(FastCgiRequestHandler::with_process_manager(
+            String::from(FASTCGI_PATH),
+            fastcgi_process_manager(fastcgi_script, &error_log),
+            String::from(STATIC_FOLDER),
+            fastcgi_settings(&error_log),
+        )));
+    } else if let Some(fastcgi_address) = FASTCGI_ADDRESS {
+        request_handlers.push(Box::new(FastCgiRequestHandler::new(
+            String::from(FASTCGI_PATH),
+            BackendAddress::parse(fastcgi_address),
+            String::from(STATIC_FOLDER),
+            fastcgi_settings(&error_log),
+        )));
+    }
+    if let Some(scgi_address) = SCGI_ADDRESS {
+        request_handlers.push(Box::new(ScgiRequestHandler::new(
+            String::from(SCGI_PATH),
+            BackendAddress::parse(scgi_address),
+            String::from(STATIC_FOLDER),
+            ScgiSettings {
+                timeout: SCGI_TIMEOUT,
+                detailed_errors: CGI_DETAILED_ERRORS,
+                ..ScgiSettings::default()
+            },
+        )));
+    }
+    request_handlers.push(Box::new(CgiRequestHandler::new(
+        String::from(CGI_PATH),
+        String::from(CGI_FOLDER),
+        StaticRequestHandler::new(String::from(STATIC_FOLDER), script_extensions()),
+        cgi_settings(&error_log, false),
+    )));
+    if CGI_IN_STATIC_FOLDER {
+        request_handlers.push(Box::new(CgiRequestHandler::new(
+            String::from(""),
+            String::from(STATIC_FOLDER),
+            StaticRequestHandler::new(String::from(STATIC_FOLDER), script_extensions()),
+            cgi_settings(&error_log, true),
+        )));
+    }
+    request_handlers.push(Box::new(StaticRequestHandler::new(
+        String::from(STATIC_FOLDER),
+        script_extensions(),
+    )));
+
+    let conn_handler = Arc::new(ConnectionHandler::new(
+        request_handlers,
+        ConnectionSettings {
+            max_body_size: MAX_BODY_SIZE,
+            keep_alive_timeout: KEEP_ALIVE_TIMEOUT,
+            max_requests_per_connection: MAX_REQUESTS_PER_CONNECTION,
+            max_persistent_connections: MAX_PERSISTENT_CONNECTIONS,
+            max_local_redirects: MAX_LOCAL_REDIRECTS,
+        },
+    ));
 
     println!("Booting up.");
 